@@ -1,7 +1,7 @@
-use std::{io::{stdin, Result}, net::SocketAddr, str::FromStr, sync::Mutex};
+use std::{io::{stdin, Result}, sync::Mutex};
 use tokio::{
   io::{self, AsyncReadExt, AsyncWriteExt},
-  net::{TcpSocket, TcpStream},
+  net::TcpSocket,
 };
 use std::sync::Arc;
 
@@ -1,4 +1,7 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
 
 /// Naive FTP server in Rust
 #[derive(Parser, Debug)]
@@ -8,6 +11,34 @@ pub struct Args {
   #[arg(long, default_value_t = String::from("./"))]
   pub folder: String,
 
+  /// Where the served files are kept, `memory` starts empty and is lost on exit, `s3` uses a bucket
+  #[arg(long, value_enum, default_value_t = StorageKind::Local)]
+  pub storage: StorageKind,
+
+  /// Let clients enter any .zip, .tar, .tar.gz or .tgz file like a read-only directory
+  #[arg(long, default_value_t = false)]
+  pub browse_archives: bool,
+
+  /// Endpoint URL of the S3-compatible service, e.g. `http://127.0.0.1:9000`
+  #[arg(long, required_if_eq("storage", "s3"))]
+  pub s3_endpoint: Option<String>,
+
+  /// Bucket holding the served files
+  #[arg(long, required_if_eq("storage", "s3"))]
+  pub s3_bucket: Option<String>,
+
+  /// Region the requests are signed for
+  #[arg(long, default_value_t = String::from("us-east-1"))]
+  pub s3_region: String,
+
+  /// Access key ID for the S3 storage
+  #[arg(long, env = "AWS_ACCESS_KEY_ID", required_if_eq("storage", "s3"))]
+  pub s3_access_key: Option<String>,
+
+  /// Secret access key for the S3 storage
+  #[arg(long, env = "AWS_SECRET_ACCESS_KEY", required_if_eq("storage", "s3"))]
+  pub s3_secret_key: Option<Secret>,
+
   /// Listening host
   #[arg(long, default_value_t = String::from("127.0.0.1"))]
   pub host: String,
@@ -15,6 +46,92 @@ pub struct Args {
   /// Listening port
   #[arg(long, default_value_t = 8180)]
   pub port: u16,
+
+  /// Server config file (TOML) with groups and access rules
+  #[arg(long)]
+  pub config: Option<String>,
+
+  /// Users file (TOML or JSON) with Argon2 password hashes
+  #[arg(long, group = "auth")]
+  pub users: Option<String>,
+
+  /// Apache htpasswd file to authenticate against
+  #[arg(long, group = "auth")]
+  pub htpasswd: Option<String>,
+
+  /// Program that receives the username and password on stdin and exits with 0 to accept the login
+  #[arg(long, group = "auth")]
+  pub auth_command: Option<String>,
+
+  /// Allow anonymous login as `anonymous` or `ftp`
+  #[arg(long, default_value_t = false)]
+  pub anonymous: bool,
+
+  /// Folder path to serve to anonymous users instead of the root folder
+  #[arg(long)]
+  pub anonymous_root: Option<String>,
+
+  /// Folder holding per-user homes (`<HOMES>/<username>`) for accounts without an explicit home
+  #[arg(long)]
+  pub homes: Option<String>,
+
+  /// PEM certificate chain enabling explicit FTPS (`AUTH TLS`)
+  #[arg(long, requires = "tls_key")]
+  pub tls_cert: Option<String>,
+
+  /// PEM private key of the TLS certificate
+  #[arg(long, requires = "tls_cert")]
+  pub tls_key: Option<String>,
+
+  /// Port of an additional implicit FTPS listener (usually 990)
+  #[arg(long, requires = "tls_cert")]
+  pub implicit_port: Option<u16>,
+
+  /// Refuse logins until the control connection is protected with `AUTH TLS`
+  #[arg(long, requires = "tls_cert")]
+  pub require_tls: bool,
+
+  /// Refuse data transfers unless the client asked for `PROT P`
+  #[arg(long, requires = "tls_cert")]
+  pub require_prot_p: bool,
+
+  /// Refuse data connections that do not resume the TLS session of the control connection
+  #[arg(long, requires = "tls_cert")]
+  pub require_tls_reuse: bool,
+
+  /// Charset of file names for clients that do not use UTF-8, e.g. `GBK` or `latin1`
+  #[arg(long)]
+  pub charset: Option<String>,
+
+  /// Print the hash of the given password for the users file and exit
+  #[arg(long)]
+  pub hash_password: Option<String>,
+}
+
+/// Storage backends selectable with `--storage`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+  Local,
+  Memory,
+  S3,
+}
+
+/// A value that is left out when the arguments are printed.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl FromStr for Secret {
+  type Err = Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(Secret(s.to_string()))
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Secret(..)")
+  }
 }
 
 impl Args {
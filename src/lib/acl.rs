@@ -0,0 +1,167 @@
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+use crate::lib::auth::Account;
+use crate::lib::permission::Permission;
+
+/// An access rule as written in the server config file:
+///
+/// ```toml
+/// [[acl]]
+/// path = "/builds/release"
+/// group = "qa"
+/// allow = ["read", "list"]
+/// deny = ["write", "delete"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRuleConfig {
+  /// Glob over virtual paths, e.g. `/builds/*/logs` or `/builds/**/logs`. A
+  /// rule covers the matched paths and everything below them.
+  pub path: String,
+  pub user: Option<String>,
+  pub group: Option<String>,
+  #[serde(default)]
+  pub allow: Vec<Permission>,
+  #[serde(default)]
+  pub deny: Vec<Permission>,
+}
+
+#[derive(Debug)]
+struct AclRule {
+  matcher: GlobMatcher,
+  subtree: GlobMatcher,
+  user: Option<String>,
+  group: Option<String>,
+  allow: Vec<Permission>,
+  deny: Vec<Permission>,
+}
+
+/// Path-scoped access rules evaluated on top of the per-user permissions.
+///
+/// Among the rules matching the user and the virtual path, a deny always wins
+/// over an allow. Without any matching rule the account permissions apply.
+#[derive(Debug, Default)]
+pub struct Acl {
+  rules: Vec<AclRule>,
+  groups: HashMap<String, Vec<String>>,
+}
+
+impl Acl {
+  pub fn new(
+    rules: &[AclRuleConfig],
+    groups: HashMap<String, Vec<String>>,
+  ) -> Result<Self, Box<dyn Error>> {
+    let mut compiled = Vec::new();
+    for rule in rules {
+      let path = rule.path.trim_end_matches('/');
+      let (matcher, subtree) = if path.is_empty() {
+        (compile("**")?, compile("**")?)
+      } else {
+        (compile(path)?, compile(&format!("{}/**", path))?)
+      };
+      compiled.push(AclRule {
+        matcher,
+        subtree,
+        user: rule.user.clone(),
+        group: rule.group.clone(),
+        allow: rule.allow.clone(),
+        deny: rule.deny.clone(),
+      });
+    }
+    Ok(Self {
+      rules: compiled,
+      groups,
+    })
+  }
+
+  pub fn is_member(&self, account: &Account, group: &str) -> bool {
+    account.groups.iter().any(|g| g == group)
+      || self
+        .groups
+        .get(group)
+        .is_some_and(|members| members.contains(&account.username))
+  }
+
+  /// Whether `account` may perform `permission` on the virtual `path`.
+  pub fn allows(&self, account: &Account, path: &str, permission: Permission) -> bool {
+    let mut allowed = None;
+    for rule in &self.rules {
+      if !(rule.matcher.is_match(path) || rule.subtree.is_match(path)) {
+        continue;
+      }
+      if rule.user.as_ref().is_some_and(|u| *u != account.username) {
+        continue;
+      }
+      if rule
+        .group
+        .as_ref()
+        .is_some_and(|g| !self.is_member(account, g))
+      {
+        continue;
+      }
+      if rule.deny.contains(&permission) {
+        return false;
+      }
+      if rule.allow.contains(&permission) {
+        allowed = Some(true);
+      }
+    }
+    allowed.unwrap_or_else(|| account.permissions.allows(permission))
+  }
+}
+
+/// `*` stays within one path segment, `**` crosses them.
+fn compile(pattern: &str) -> Result<GlobMatcher, Box<dyn Error>> {
+  Ok(
+    GlobBuilder::new(pattern)
+      .literal_separator(true)
+      .build()
+      .map_err(|e| format!("Invalid ACL path {}: {}", pattern, e))?
+      .compile_matcher(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lib::permission::Permissions;
+
+  fn rule(path: &str, group: &str, allow: Vec<Permission>, deny: Vec<Permission>) -> AclRuleConfig {
+    AclRuleConfig {
+      path: path.to_string(),
+      user: None,
+      group: Some(group.to_string()),
+      allow,
+      deny,
+    }
+  }
+
+  #[test]
+  fn test_acl() {
+    let rules = vec![
+      rule("/builds/incoming", "qa", vec![Permission::Write], vec![]),
+      rule("/builds/release", "qa", vec![], vec![Permission::Write]),
+      rule("/builds/*/secret", "qa", vec![], vec![Permission::Read]),
+    ];
+    let groups = HashMap::from([("qa".to_string(), vec!["alice".to_string()])]);
+    let acl = Acl::new(&rules, groups).unwrap();
+
+    let mut alice = Account::new("alice");
+    alice.permissions = Permissions::read_only();
+    assert!(acl.allows(&alice, "/builds/incoming/a.tar", Permission::Write));
+    assert!(acl.allows(&alice, "/builds/incoming", Permission::Write));
+    assert!(!acl.allows(&alice, "/builds/release/a.tar", Permission::Write));
+    assert!(acl.allows(&alice, "/builds/release/a.tar", Permission::Read));
+    assert!(!acl.allows(&alice, "/builds/release/secret/key", Permission::Read));
+    assert!(acl.allows(&alice, "/builds/a/b/secret", Permission::Read));
+    assert!(!acl.allows(&alice, "/other", Permission::Write));
+
+    let mut bob = Account::new("bob");
+    bob.permissions = Permissions::read_only();
+    assert!(!acl.allows(&bob, "/builds/incoming/a.tar", Permission::Write));
+    bob.groups = vec!["qa".to_string()];
+    assert!(acl.allows(&bob, "/builds/incoming/a.tar", Permission::Write));
+  }
+}
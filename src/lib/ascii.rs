@@ -0,0 +1,156 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Converts local line endings to the `\r\n` of ASCII mode transfers, one
+/// chunk at a time.
+#[derive(Debug)]
+pub struct Encoder {
+  last: u8,
+  skip: u64,
+}
+
+impl Encoder {
+  /// `skip` drops the first bytes of the converted data, which is how a
+  /// download restarted with `REST` resumes.
+  pub fn new(skip: u64) -> Self {
+    Self { last: 0, skip }
+  }
+
+  pub fn encode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+    for &byte in input {
+      if byte == b'\n' && self.last != b'\r' {
+        self.push(b'\r', output);
+      }
+      self.push(byte, output);
+      self.last = byte;
+    }
+  }
+
+  fn push(&mut self, byte: u8, output: &mut Vec<u8>) {
+    if self.skip > 0 {
+      self.skip -= 1;
+    } else {
+      output.push(byte);
+    }
+  }
+}
+
+/// Converts the `\r\n` of ASCII mode transfers to local line endings. A `\r`
+/// ending a chunk is held back until the next chunk shows what follows it.
+#[derive(Debug, Default)]
+pub struct Decoder {
+  pending_cr: bool,
+}
+
+impl Decoder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn decode(&mut self, input: &[u8], output: &mut Vec<u8>) {
+    for &byte in input {
+      if self.pending_cr {
+        self.pending_cr = false;
+        if byte != b'\n' {
+          output.push(b'\r');
+        }
+      }
+      if byte == b'\r' {
+        self.pending_cr = true;
+      } else {
+        output.push(byte);
+      }
+    }
+  }
+
+  /// Flushes a held back `\r` at the end of the transfer.
+  pub fn finish(&mut self, output: &mut Vec<u8>) {
+    if self.pending_cr {
+      self.pending_cr = false;
+      output.push(b'\r');
+    }
+  }
+}
+
+/// Length of `reader` once converted for an ASCII mode transfer.
+pub async fn network_size(mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+  let mut buf = vec![0; 64 * 1024];
+  let mut size = 0;
+  let mut last = 0;
+  loop {
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(size);
+    }
+    for &byte in &buf[..n] {
+      if byte == b'\n' && last != b'\r' {
+        size += 1;
+      }
+      size += 1;
+      last = byte;
+    }
+  }
+}
+
+/// Position in the local file where an upload restarted at `offset` bytes of
+/// ASCII data continues. An offset splitting a converted `\r\n` maps to the
+/// `\n`, which the client sends again.
+pub async fn restart_position(mut reader: impl AsyncRead + Unpin, offset: u64) -> io::Result<u64> {
+  let mut buf = vec![0; 64 * 1024];
+  let mut position = 0;
+  let mut size = 0;
+  let mut last = 0;
+  loop {
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(position);
+    }
+    for &byte in &buf[..n] {
+      let width = if byte == b'\n' && last != b'\r' { 2 } else { 1 };
+      if size + width > offset {
+        return Ok(position);
+      }
+      size += width;
+      position += 1;
+      last = byte;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(input: &[&[u8]], skip: u64) -> Vec<u8> {
+    let mut encoder = Encoder::new(skip);
+    let mut output = Vec::new();
+    for chunk in input {
+      encoder.encode(chunk, &mut output);
+    }
+    output
+  }
+
+  fn decode(input: &[&[u8]]) -> Vec<u8> {
+    let mut decoder = Decoder::new();
+    let mut output = Vec::new();
+    for chunk in input {
+      decoder.decode(chunk, &mut output);
+    }
+    decoder.finish(&mut output);
+    output
+  }
+
+  #[tokio::test]
+  async fn test_ascii() {
+    assert_eq!(encode(&[b"a\nb\r", b"\nc\n"], 0), b"a\r\nb\r\nc\r\n");
+    assert_eq!(encode(&[b"a\nb\n"], 2), b"\nb\r\n");
+    assert_eq!(decode(&[b"a\r", b"\nb\rc\r"]), b"a\nb\rc\r");
+    assert_eq!(network_size(&b"a\nb\r\n"[..]).await.unwrap(), 6);
+
+    let file = &b"ab\ncd\n"[..];
+    assert_eq!(restart_position(file, 2).await.unwrap(), 2);
+    assert_eq!(restart_position(file, 3).await.unwrap(), 2);
+    assert_eq!(restart_position(file, 4).await.unwrap(), 3);
+    assert_eq!(restart_position(file, 100).await.unwrap(), 6);
+  }
+}
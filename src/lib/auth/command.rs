@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::{Account, AccountDetails, AuthError, Authenticator};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delegates logins to an external program.
+///
+/// The program receives the username and the password on two lines of its
+/// stdin and accepts the login by exiting with status 0. It may print a JSON
+/// object with `home`, `permissions` and `quota` to override the defaults.
+#[derive(Debug)]
+pub struct CommandAuthenticator {
+  program: String,
+}
+
+impl CommandAuthenticator {
+  pub fn new(program: &str) -> Self {
+    Self {
+      program: program.to_string(),
+    }
+  }
+}
+
+#[async_trait]
+impl Authenticator for CommandAuthenticator {
+  async fn authenticate(
+    &self,
+    username: &str,
+    password: &str,
+  ) -> Result<Option<Account>, AuthError> {
+    let mut child = Command::new(&self.program)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::inherit())
+      .kill_on_drop(true)
+      .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+      stdin
+        .write_all(format!("{}\n{}\n", username, password).as_bytes())
+        .await?;
+    }
+
+    let output = tokio::time::timeout(COMMAND_TIMEOUT, child.wait_with_output())
+      .await
+      .map_err(|_| format!("Auth command timed out: {}", self.program))??;
+    if !output.status.success() {
+      return Ok(None);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let details = if stdout.trim().is_empty() {
+      AccountDetails::default()
+    } else {
+      serde_json::from_str(stdout.trim())?
+    };
+    Ok(Some(Account::new(username).with_details(details)))
+  }
+}
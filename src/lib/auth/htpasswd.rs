@@ -0,0 +1,226 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+
+use super::{Account, AuthError, Authenticator};
+
+const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Accounts from an Apache `htpasswd` file. Supports bcrypt (`$2y$`), APR1
+/// MD5 (`$apr1$`), MD5-crypt (`$1$`) and SHA-1 (`{SHA}`) entries.
+#[derive(Debug, Default)]
+pub struct HtpasswdAuthenticator {
+  users: Arc<HashMap<String, String>>,
+}
+
+impl HtpasswdAuthenticator {
+  pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    Ok(Self {
+      users: Arc::new(parse_htpasswd(&content)),
+    })
+  }
+}
+
+#[async_trait]
+impl Authenticator for HtpasswdAuthenticator {
+  async fn authenticate(
+    &self,
+    username: &str,
+    password: &str,
+  ) -> Result<Option<Account>, AuthError> {
+    let hash = match self.users.get(username) {
+      Some(hash) => hash.clone(),
+      None => return Ok(None),
+    };
+    let password = password.to_string();
+    let verified = tokio::task::spawn_blocking(move || verify_hash(&password, &hash)).await?;
+    Ok(verified.then(|| Account::new(username)))
+  }
+}
+
+/// Entries with a hash format that is not supported are reported and left
+/// out, so that those users cannot log in.
+fn parse_htpasswd(content: &str) -> HashMap<String, String> {
+  let mut users = HashMap::new();
+  for (number, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let Some((user, hash)) = line.split_once(':') else {
+      continue;
+    };
+    if !is_supported(hash) {
+      println!(
+        "Unsupported htpasswd hash format for user {} on line {}",
+        user,
+        number + 1
+      );
+      continue;
+    }
+    users.insert(user.to_string(), hash.to_string());
+  }
+  users
+}
+
+fn is_supported(hash: &str) -> bool {
+  if let Some(rest) = hash
+    .strip_prefix("$apr1$")
+    .or_else(|| hash.strip_prefix("$1$"))
+  {
+    // MD5-crypt salts are made of the crypt alphabet.
+    return salt_of(rest).bytes().all(|byte| ITOA64.contains(&byte));
+  }
+  ["$2", "{SHA}"]
+    .iter()
+    .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+  if hash.starts_with("$2") {
+    bcrypt::verify(password, hash).unwrap_or(false)
+  } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+    constant_time_eq(
+      md5_crypt(password, salt_of(rest), "$apr1$").as_bytes(),
+      hash.as_bytes(),
+    )
+  } else if let Some(rest) = hash.strip_prefix("$1$") {
+    constant_time_eq(
+      md5_crypt(password, salt_of(rest), "$1$").as_bytes(),
+      hash.as_bytes(),
+    )
+  } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+    let expected = STANDARD.encode(Sha1::digest(password.as_bytes()));
+    constant_time_eq(expected.as_bytes(), digest.as_bytes())
+  } else {
+    false
+  }
+}
+
+/// Compares in a time that depends on the length only, like bcrypt does, so
+/// that the time taken does not tell how much of a hash matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The salt of an MD5-crypt hash, which is at most 8 characters long.
+fn salt_of(rest: &str) -> &str {
+  let salt = rest.split('$').next().unwrap_or("");
+  match salt.char_indices().nth(8) {
+    Some((end, _)) => &salt[..end],
+    None => salt,
+  }
+}
+
+/// The FreeBSD MD5-crypt algorithm, which APR1 reuses with its own magic.
+fn md5_crypt(password: &str, salt: &str, magic: &str) -> String {
+  let pw = password.as_bytes();
+  let salt_bytes = salt.as_bytes();
+
+  let alternate = Md5::new()
+    .chain_update(pw)
+    .chain_update(salt_bytes)
+    .chain_update(pw)
+    .finalize();
+
+  let mut ctx = Md5::new()
+    .chain_update(pw)
+    .chain_update(magic.as_bytes())
+    .chain_update(salt_bytes);
+  let mut remaining = pw.len();
+  while remaining > 0 {
+    let n = remaining.min(16);
+    ctx.update(&alternate[..n]);
+    remaining -= n;
+  }
+  let mut i = pw.len();
+  while i > 0 {
+    if i & 1 == 1 {
+      ctx.update([0u8]);
+    } else {
+      ctx.update(&pw[..1]);
+    }
+    i >>= 1;
+  }
+  let mut digest = ctx.finalize();
+
+  for round in 0..1000 {
+    let mut ctx = Md5::new();
+    if round & 1 == 1 {
+      ctx.update(pw);
+    } else {
+      ctx.update(digest);
+    }
+    if round % 3 != 0 {
+      ctx.update(salt_bytes);
+    }
+    if round % 7 != 0 {
+      ctx.update(pw);
+    }
+    if round & 1 == 1 {
+      ctx.update(digest);
+    } else {
+      ctx.update(pw);
+    }
+    digest = ctx.finalize();
+  }
+
+  let mut encoded = String::new();
+  let mut push = |value: u32, n: usize| {
+    let mut value = value;
+    for _ in 0..n {
+      encoded.push(ITOA64[(value & 0x3f) as usize] as char);
+      value >>= 6;
+    }
+  };
+  for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+    push(
+      ((digest[a] as u32) << 16) | ((digest[b] as u32) << 8) | digest[c] as u32,
+      4,
+    );
+  }
+  push(digest[11] as u32, 2);
+
+  format!("{}{}${}", magic, salt, encoded)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_htpasswd_hashes() {
+    let users = parse_htpasswd(
+      "# comment\n\
+       apr:$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0\n\
+       md5:$1$abc$Or2rbeUYTvt12aiVzMuS/.\n\
+       sha:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+       plain:secret\n\
+       multibyte:$apr1$saltsalé$LrttParrLPdxvgutaSXWJ0\n",
+    );
+    assert_eq!(users.len(), 3);
+    assert!(!users.contains_key("plain"));
+    assert!(!users.contains_key("multibyte"));
+    assert!(!verify_hash(
+      "secret",
+      "$apr1$saltsalé$LrttParrLPdxvgutaSXWJ0"
+    ));
+    assert!(verify_hash("secret", &users["apr"]));
+    assert!(!verify_hash("wrong", &users["apr"]));
+    assert!(verify_hash("", &users["md5"]));
+    assert!(verify_hash("secret", &users["sha"]));
+    assert!(!verify_hash("secreu", &users["sha"]));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+
+    let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+    assert!(verify_hash("secret", &bcrypt_hash));
+    assert!(!verify_hash("wrong", &bcrypt_hash));
+  }
+}
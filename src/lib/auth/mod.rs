@@ -0,0 +1,93 @@
+mod command;
+mod htpasswd;
+mod static_file;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::arg_parser::Args;
+use crate::lib::permission::Permissions;
+
+pub use command::CommandAuthenticator;
+pub use htpasswd::HtpasswdAuthenticator;
+pub use static_file::{hash_password, StaticFileAuthenticator};
+
+pub type AuthError = Box<dyn Error + Send + Sync>;
+
+/// Login names accepted for anonymous access when it is enabled.
+const ANONYMOUS_NAMES: [&str; 2] = ["anonymous", "ftp"];
+
+/// An authenticated user as resolved by an `Authenticator`.
+#[derive(Debug, Clone)]
+pub struct Account {
+  pub username: String,
+  /// Root directory of the account, the server root when `None`.
+  pub home: Option<String>,
+  pub permissions: Permissions,
+  /// Maximum number of bytes the account may store, unlimited when `None`.
+  pub quota: Option<u64>,
+  pub groups: Vec<String>,
+}
+
+impl Account {
+  pub fn new(username: &str) -> Self {
+    Self {
+      username: username.to_string(),
+      home: None,
+      permissions: Permissions::default(),
+      quota: None,
+      groups: Vec::new(),
+    }
+  }
+
+  pub fn with_details(mut self, details: AccountDetails) -> Self {
+    self.home = details.home.or(self.home);
+    self.permissions = details.permissions.unwrap_or(self.permissions);
+    self.quota = details.quota.or(self.quota);
+    self.groups = details.groups.unwrap_or(self.groups);
+    self
+  }
+}
+
+/// Optional account settings shared by the backends that can carry them.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AccountDetails {
+  pub home: Option<String>,
+  pub permissions: Option<Permissions>,
+  pub quota: Option<u64>,
+  pub groups: Option<Vec<String>>,
+}
+
+/// Identity source consulted by `PASS`.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+  /// Returns the account for the given credentials, or `None` when they are
+  /// rejected. Errors are reserved for backend failures.
+  async fn authenticate(
+    &self,
+    username: &str,
+    password: &str,
+  ) -> Result<Option<Account>, AuthError>;
+}
+
+pub fn is_anonymous(username: &str) -> bool {
+  ANONYMOUS_NAMES.contains(&username.to_lowercase().as_str())
+}
+
+/// Builds the authenticator selected on the command line. Without any backend
+/// only anonymous logins (if enabled) are possible.
+pub fn from_args(cfg: &Args) -> Result<Arc<dyn Authenticator>, Box<dyn Error>> {
+  if let Some(path) = &cfg.users {
+    return Ok(Arc::new(StaticFileAuthenticator::load(path)?));
+  }
+  if let Some(path) = &cfg.htpasswd {
+    return Ok(Arc::new(HtpasswdAuthenticator::load(path)?));
+  }
+  if let Some(program) = &cfg.auth_command {
+    return Ok(Arc::new(CommandAuthenticator::new(program)));
+  }
+  Ok(Arc::new(StaticFileAuthenticator::default()))
+}
@@ -0,0 +1,114 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{Account, AccountDetails, AuthError, Authenticator};
+
+#[derive(Debug, Deserialize)]
+struct UserEntry {
+  username: String,
+  /// Argon2 hash in PHC string format, e.g. `$argon2id$v=19$...`
+  password: String,
+  #[serde(flatten)]
+  details: AccountDetails,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct UsersFile {
+  #[serde(default)]
+  users: Vec<UserEntry>,
+}
+
+/// Accounts listed in a TOML or JSON users file (picked by extension).
+///
+/// Each entry holds a `username`, a salted Argon2 `password` hash as produced
+/// by `--hash-password`, and optionally `home`, `permissions` and `quota`.
+#[derive(Debug, Default)]
+pub struct StaticFileAuthenticator {
+  users: Arc<HashMap<String, UserEntry>>,
+}
+
+impl StaticFileAuthenticator {
+  pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let file: UsersFile = match Path::new(path).extension().and_then(|e| e.to_str()) {
+      Some("json") => serde_json::from_str(&content)?,
+      _ => toml::from_str(&content)?,
+    };
+
+    let mut users = HashMap::new();
+    for entry in file.users {
+      // Reject malformed hashes at startup rather than at login time.
+      PasswordHash::new(&entry.password)
+        .map_err(|e| format!("Invalid password hash for {}: {}", entry.username, e))?;
+      users.insert(entry.username.clone(), entry);
+    }
+    Ok(Self {
+      users: Arc::new(users),
+    })
+  }
+}
+
+#[async_trait]
+impl Authenticator for StaticFileAuthenticator {
+  async fn authenticate(
+    &self,
+    username: &str,
+    password: &str,
+  ) -> Result<Option<Account>, AuthError> {
+    let users = self.users.clone();
+    let username = username.to_string();
+    let password = password.to_string();
+    // Argon2 is deliberately slow, keep it off the async workers.
+    let account = tokio::task::spawn_blocking(move || {
+      let entry = users.get(&username)?;
+      let parsed = PasswordHash::new(&entry.password).ok()?;
+      Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .ok()?;
+      Some(Account::new(&username).with_details(entry.details.clone()))
+    })
+    .await?;
+    Ok(account)
+  }
+}
+
+pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hash = Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(|e| e.to_string())?;
+  Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_static_file_authenticator() {
+    let path = std::env::temp_dir().join("rftp_test_users.toml");
+    let hash = hash_password("secret").unwrap();
+    fs::write(
+      &path,
+      format!(
+        "[[users]]\nusername = \"alice\"\npassword = \"{}\"\nhome = \"/srv/ftp/alice\"\n",
+        hash
+      ),
+    )
+    .unwrap();
+
+    let auth = StaticFileAuthenticator::load(path.to_str().unwrap()).unwrap();
+    let account = auth.authenticate("alice", "secret").await.unwrap().unwrap();
+    assert_eq!(account.home.as_deref(), Some("/srv/ftp/alice"));
+    assert!(auth.authenticate("alice", "wrong").await.unwrap().is_none());
+    assert!(auth.authenticate("bob", "secret").await.unwrap().is_none());
+  }
+}
@@ -0,0 +1,91 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Descriptor flags of a block header (RFC 959, section 3.4.2). Records are
+// not supported, so neither are the end of record and error flags.
+pub const END_OF_FILE: u8 = 0x40;
+pub const RESTART_MARKER: u8 = 0x10;
+
+/// A `MODE B` block, at most 65535 bytes of data behind a 3 byte header.
+#[derive(Debug)]
+pub struct Block {
+  pub descriptor: u8,
+  pub data: Vec<u8>,
+}
+
+impl Block {
+  pub fn is_restart_marker(&self) -> bool {
+    self.descriptor & RESTART_MARKER != 0
+  }
+
+  pub fn is_end_of_file(&self) -> bool {
+    self.descriptor & END_OF_FILE != 0
+  }
+}
+
+/// Writes `data` as blocks with `descriptor`, splitting it where it does not
+/// fit into a single block.
+pub async fn write_block(
+  writer: &mut (impl AsyncWrite + Unpin + ?Sized),
+  descriptor: u8,
+  data: &[u8],
+) -> io::Result<()> {
+  let mut chunks = data.chunks(u16::MAX as usize).peekable();
+  if chunks.peek().is_none() {
+    return writer.write_all(&[descriptor, 0, 0]).await;
+  }
+  for chunk in chunks {
+    let len = (chunk.len() as u16).to_be_bytes();
+    writer.write_all(&[descriptor, len[0], len[1]]).await?;
+    writer.write_all(chunk).await?;
+  }
+  Ok(())
+}
+
+/// Reads the next block, or `None` if the connection was closed in between
+/// blocks.
+pub async fn read_block(
+  reader: &mut (impl AsyncRead + Unpin + ?Sized),
+) -> io::Result<Option<Block>> {
+  let mut header = [0u8; 3];
+  let n = reader.read(&mut header[..1]).await?;
+  if n == 0 {
+    return Ok(None);
+  }
+  reader.read_exact(&mut header[1..]).await?;
+  let mut data = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+  reader.read_exact(&mut data).await?;
+  Ok(Some(Block {
+    descriptor: header[0],
+    data,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_block() {
+    let mut buf = Vec::new();
+    write_block(&mut buf, 0, b"hello").await.unwrap();
+    write_block(&mut buf, RESTART_MARKER, b"5").await.unwrap();
+    write_block(&mut buf, END_OF_FILE, b"").await.unwrap();
+    assert_eq!(buf, b"\x00\x00\x05hello\x10\x00\x015\x40\x00\x00");
+
+    let mut reader = &buf[..];
+    let block = read_block(&mut reader).await.unwrap().unwrap();
+    assert_eq!(block.data, b"hello");
+    assert!(read_block(&mut reader)
+      .await
+      .unwrap()
+      .unwrap()
+      .is_restart_marker());
+    assert!(read_block(&mut reader)
+      .await
+      .unwrap()
+      .unwrap()
+      .is_end_of_file());
+    assert!(read_block(&mut reader).await.unwrap().is_none());
+  }
+}
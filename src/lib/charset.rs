@@ -0,0 +1,117 @@
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// Charset of file names exchanged with the client (RFC 2640). Everything is
+/// UTF-8 unless a fallback charset is configured for clients that do not
+/// speak it.
+#[derive(Debug, Clone, Copy)]
+pub struct Charset {
+  fallback: &'static Encoding,
+}
+
+impl Default for Charset {
+  fn default() -> Self {
+    Self { fallback: UTF_8 }
+  }
+}
+
+impl Charset {
+  /// Charset falling back to the encoding named `label`, e.g. `GBK`.
+  pub fn new(label: &str) -> Result<Self, String> {
+    match Encoding::for_label(label.as_bytes()) {
+      Some(fallback) => Ok(Self { fallback }),
+      None => Err(format!("Unknown charset: {}", label)),
+    }
+  }
+
+  /// Whether a charset other than UTF-8 is configured.
+  pub fn has_fallback(&self) -> bool {
+    self.fallback != UTF_8
+  }
+
+  /// Encoding of the replies and listings sent to the client, `None` for
+  /// UTF-8.
+  pub fn encoding(&self, utf8: bool) -> Option<&'static Encoding> {
+    (!utf8 && self.has_fallback()).then_some(self.fallback)
+  }
+
+  /// Decodes text from the client, which is taken as UTF-8 whenever it is
+  /// valid and otherwise decoded with the fallback charset.
+  pub fn decode(&self, bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+      Ok(text) => text.to_string(),
+      Err(_) => self
+        .fallback
+        .decode_without_bom_handling(bytes)
+        .0
+        .into_owned(),
+    }
+  }
+
+  /// Encodes text for the client, in UTF-8 if `utf8` is set.
+  pub fn encode<'a>(&self, text: &'a str, utf8: bool) -> Cow<'a, [u8]> {
+    match self.encoding(utf8) {
+      Some(encoding) => encoding.encode(text).0,
+      None => Cow::Borrowed(text.as_bytes()),
+    }
+  }
+
+  /// Name of a file on disk as text, decoded with the fallback charset if it
+  /// is not valid UTF-8.
+  pub fn file_name(&self, name: &OsStr) -> String {
+    self.decode(name.as_bytes())
+  }
+
+  /// Finds `path` on disk if some of its names are stored in the fallback
+  /// charset instead of UTF-8. Names that exist in neither stay UTF-8, so
+  /// new files are always created with UTF-8 names.
+  pub fn disk_path(&self, path: PathBuf) -> PathBuf {
+    if !self.has_fallback() || path.exists() {
+      return path;
+    }
+    let mut disk = PathBuf::new();
+    for component in path.components() {
+      let utf8 = disk.join(component);
+      if utf8.exists() {
+        disk = utf8;
+        continue;
+      }
+      let legacy = match component.as_os_str().to_str() {
+        Some(name) => disk.join(OsStr::from_bytes(&self.fallback.encode(name).0)),
+        None => utf8.clone(),
+      };
+      disk = if legacy.exists() { legacy } else { utf8 };
+    }
+    disk
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_charset() {
+    let gbk = Charset::new("GBK").unwrap();
+    assert!(Charset::new("bogus").is_err());
+    assert_eq!(gbk.decode("中文".as_bytes()), "中文");
+    assert_eq!(gbk.decode(b"\xd6\xd0\xce\xc4"), "中文");
+    assert_eq!(&*gbk.encode("中文", false), b"\xd6\xd0\xce\xc4");
+    assert_eq!(&*gbk.encode("中文", true), "中文".as_bytes());
+    assert_eq!(
+      &*Charset::default().encode("中文", false),
+      "中文".as_bytes()
+    );
+
+    let root = std::env::temp_dir().join("rftp-test-charset");
+    let legacy = root.join(OsStr::from_bytes(b"\xd6\xd0\xce\xc4"));
+    std::fs::create_dir_all(&legacy).unwrap();
+    assert_eq!(gbk.disk_path(root.join("中文")), legacy);
+    assert_eq!(gbk.disk_path(root.join("中文/新")), legacy.join("新"));
+    assert_eq!(gbk.file_name(legacy.file_name().unwrap()), "中文");
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+}
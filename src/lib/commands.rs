@@ -1,6 +1,11 @@
-use std::net::SocketAddr;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::lib::permission::Permission;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum FtpCommand {
   // Basic commands
   USER(String),
@@ -13,6 +18,7 @@ pub enum FtpCommand {
   QUIT,
   SYST,
   TYPE(String),
+  MODE(String),
   RNFR(String),
   RNTO(String),
   PWD,
@@ -34,6 +40,138 @@ pub enum FtpCommand {
 
   FEAT,
   MDTM(String),
+  SIZE(String),
+
+  // Extensions of FTP (RFC 3659)
+  MLSD(Option<String>),
+  MLST(Option<String>),
+  OPTS(String),
+
+  // Internationalization (RFC 2640)
+  LANG(Option<String>),
+
+  // Setting file facts (draft-somers-ftp-mfxx), as facts and path
+  MFMT(String, String),
+  MFCT(String, String),
+  MFF(String, String),
+
+  // File hashes (draft-bryan-ftp-hash and the older X* commands)
+  HASH(String),
+  RANG(String),
+  XCRC(String),
+  XMD5(String),
+  XSHA1(String),
+  XSHA256(String),
+  XSHA512(String),
+
+  // Extensions for IPv6 and NATs (RFC 2428)
+  /// `None` for a network protocol other than IPv4 (1) and IPv6 (2).
+  EPRT(Option<SocketAddr>),
+  EPSV(Option<String>),
+
+  // Security extensions (RFC 4217)
+  AUTH(String),
+  PBSZ(String),
+  PROT(String),
+}
+
+impl FtpCommand {
+  /// Whether the command may only be issued by an authenticated session.
+  pub fn requires_login(&self) -> bool {
+    !matches!(
+      self,
+      FtpCommand::USER(_)
+        | FtpCommand::PASS(_)
+        | FtpCommand::QUIT
+        | FtpCommand::SYST
+        | FtpCommand::FEAT
+        | FtpCommand::LANG(_)
+        | FtpCommand::OPTS(_)
+        | FtpCommand::NOOP
+        | FtpCommand::AUTH(_)
+        | FtpCommand::PBSZ(_)
+        | FtpCommand::PROT(_)
+    )
+  }
+
+  /// The permission the logged in user needs for the command, if any.
+  pub fn required_permission(&self) -> Option<Permission> {
+    match self {
+      FtpCommand::RETR(_)
+      | FtpCommand::HASH(_)
+      | FtpCommand::XCRC(_)
+      | FtpCommand::XMD5(_)
+      | FtpCommand::XSHA1(_)
+      | FtpCommand::XSHA256(_)
+      | FtpCommand::XSHA512(_) => Some(Permission::Read),
+      FtpCommand::STOR(_)
+      | FtpCommand::STOU
+      | FtpCommand::APPE(_)
+      | FtpCommand::MFMT(..)
+      | FtpCommand::MFCT(..)
+      | FtpCommand::MFF(..) => Some(Permission::Write),
+      FtpCommand::DELE(_) | FtpCommand::RMD(_) => Some(Permission::Delete),
+      FtpCommand::MKD(_) => Some(Permission::Mkdir),
+      FtpCommand::RNFR(_) | FtpCommand::RNTO(_) => Some(Permission::Rename),
+      FtpCommand::LIST(_)
+      | FtpCommand::NLST(_)
+      | FtpCommand::STAT(Some(_))
+      | FtpCommand::MDTM(_)
+      | FtpCommand::SIZE(_)
+      | FtpCommand::MLSD(_)
+      | FtpCommand::MLST(_) => Some(Permission::List),
+      _ => None,
+    }
+  }
+
+  /// Whether the command opens or uses a data connection.
+  pub fn uses_data_connection(&self) -> bool {
+    matches!(
+      self,
+      FtpCommand::PORT(_)
+        | FtpCommand::PASV
+        | FtpCommand::EPRT(_)
+        | FtpCommand::EPSV(_)
+        | FtpCommand::RETR(_)
+        | FtpCommand::STOR(_)
+        | FtpCommand::STOU
+        | FtpCommand::APPE(_)
+        | FtpCommand::LIST(_)
+        | FtpCommand::NLST(_)
+        | FtpCommand::MLSD(_)
+    )
+  }
+
+  /// The path the command operates on, relative to the working directory.
+  pub fn target_path(&self) -> &str {
+    match self {
+      FtpCommand::RETR(path)
+      | FtpCommand::STOR(path)
+      | FtpCommand::APPE(path)
+      | FtpCommand::DELE(path)
+      | FtpCommand::RMD(path)
+      | FtpCommand::MKD(path)
+      | FtpCommand::RNFR(path)
+      | FtpCommand::RNTO(path)
+      | FtpCommand::MDTM(path)
+      | FtpCommand::SIZE(path)
+      | FtpCommand::HASH(path)
+      | FtpCommand::XCRC(path)
+      | FtpCommand::XMD5(path)
+      | FtpCommand::XSHA1(path)
+      | FtpCommand::XSHA256(path)
+      | FtpCommand::XSHA512(path)
+      | FtpCommand::MFMT(_, path)
+      | FtpCommand::MFCT(_, path)
+      | FtpCommand::MFF(_, path)
+      | FtpCommand::LIST(Some(path))
+      | FtpCommand::NLST(Some(path))
+      | FtpCommand::MLSD(Some(path))
+      | FtpCommand::MLST(Some(path))
+      | FtpCommand::STAT(Some(path)) => path,
+      _ => ".",
+    }
+  }
 }
 
 fn empty_to_some(s: String) -> Option<String> {
@@ -44,55 +182,264 @@ fn empty_to_some(s: String) -> Option<String> {
   }
 }
 
-pub fn parse_command(req: String) -> FtpCommand {
+/// Parses the `EPRT` argument, e.g. `|1|132.235.1.2|6275|` or `|2|::1|5282|`.
+/// The first character is the delimiter. Other network protocols parse to
+/// `Some(None)`, so that they can be answered with the supported ones.
+fn parse_extended_addr(arg: &str) -> Option<Option<SocketAddr>> {
+  let delimiter = arg.chars().next()?;
+  let mut fields = arg.split(delimiter).skip(1);
+  let protocol = fields.next()?.parse::<u8>().ok()?;
+  let ip = fields.next()?;
+  let port = fields.next()?.parse::<u16>().ok()?;
+  if !matches!(protocol, 1 | 2) {
+    return Some(None);
+  }
+  match (protocol, ip.parse::<IpAddr>().ok()?) {
+    (1, ip @ IpAddr::V4(_)) | (2, ip @ IpAddr::V6(_)) => Some(Some(SocketAddr::new(ip, port))),
+    _ => None,
+  }
+}
+
+/// Splits `<value> <path>` of the MFxx commands, the path may contain spaces.
+fn split_value_path(arg: &str) -> (String, String) {
+  match arg.split_once(' ') {
+    Some((value, path)) => (value.to_string(), path.to_string()),
+    None => (arg.to_string(), String::new()),
+  }
+}
+
+/// Parses `SITE UTIME` into the time and path of the equivalent `MFMT`. Both
+/// the `<time> <path>` form and the `<path> <atime> <mtime> <ctime> UTC` form
+/// are understood.
+fn parse_site_utime(arg: &str) -> Option<(String, String)> {
+  let words = arg.split(' ').collect::<Vec<&str>>();
+  match words.as_slice() {
+    [path @ .., _, mtime, _, utc] if !path.is_empty() && utc.eq_ignore_ascii_case("UTC") => {
+      Some((mtime.to_string(), path.join(" ")))
+    }
+    [time, path @ ..] if !path.is_empty() => Some((time.to_string(), path.join(" "))),
+    _ => None,
+  }
+}
+
+/// Commands that are part of FTP but not implemented by this server.
+const NOT_IMPLEMENTED: [&str; 10] = [
+  "ACCT", "SMNT", "REIN", "STRU", "HELP", "ADAT", "CCC", "CONF", "ENC", "MIC",
+];
+
+/// Why a command line was refused, with the verb it was about. Each kind is
+/// answered with its own reply code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+  /// Not an FTP command at all (500).
+  Unrecognized(String),
+  /// A missing or malformed argument (501).
+  InvalidArgument(String),
+  /// An FTP command this server does not implement (502).
+  NotImplemented(String),
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ParseError::Unrecognized(verb) => write!(f, "Unrecognized command: {}", verb),
+      ParseError::InvalidArgument(verb) => write!(f, "Invalid argument of {}", verb),
+      ParseError::NotImplemented(verb) => write!(f, "Not implemented: {}", verb),
+    }
+  }
+}
+
+impl Error for ParseError {}
+
+/// Parses the `PORT` argument, e.g. `132,235,1,2,24,131`.
+fn parse_port_addr(arg: &str) -> Option<SocketAddr> {
+  let numbers = arg
+    .split(',')
+    .map(|n| n.trim().parse::<u8>())
+    .collect::<Result<Vec<u8>, _>>()
+    .ok()?;
+  match numbers[..] {
+    // FTP uses two bytes for the port number
+    [a, b, c, d, p1, p2] => Some(SocketAddr::from((
+      [a, b, c, d],
+      u16::from_be_bytes([p1, p2]),
+    ))),
+    _ => None,
+  }
+}
+
+/// Parses a command line, with the verb in any case.
+pub fn parse_command(req: &str) -> Result<FtpCommand, ParseError> {
   let req = req.trim();
-  let mut iter = req.split_whitespace();
-  let cmd = iter.next().unwrap();
-  let arg = iter.collect::<Vec<&str>>().join(" ");
-  match cmd {
-    "USER" => FtpCommand::USER(arg),
-    "PASS" => FtpCommand::PASS(arg),
-    "PORT" => {
-      let mut iter = arg.split(',').map(|s| s.parse::<u8>().unwrap());
-      let ip = iter
-        .by_ref()
-        .take(4)
-        .map(|i| i.to_string())
-        .collect::<Vec<String>>()
-        .join(".");
-      let p1 = iter.next().unwrap() as u16;
-      let p2 = iter.next().unwrap() as u16;
-      let port = p1 * 256 + p2; // FTP uses two bytes for the port number
-      let addr = format!("{}:{}", ip, port);
-      FtpCommand::PORT(addr.parse().unwrap())
+  let (verb, arg) = req.split_once(' ').unwrap_or((req, ""));
+  let verb = verb.to_ascii_uppercase();
+  let arg = arg.trim().to_string();
+  let invalid = || ParseError::InvalidArgument(verb.clone());
+  let required = |arg: String| {
+    if arg.is_empty() {
+      Err(invalid())
+    } else {
+      Ok(arg)
     }
+  };
+  let number = |arg: &str| {
+    // `ALLO` may be followed by a record size, e.g. `ALLO 1024 R 128`.
+    let first = arg.split(' ').next().unwrap_or_default();
+    first.parse::<u64>().map_err(|_| invalid())
+  };
+  let value_path = |arg: &str| match split_value_path(arg) {
+    (value, path) if !value.is_empty() && !path.is_empty() => Ok((value, path)),
+    _ => Err(invalid()),
+  };
+
+  let cmd = match verb.as_str() {
+    "USER" => FtpCommand::USER(required(arg)?),
+    "PASS" => FtpCommand::PASS(arg),
+    "PORT" => FtpCommand::PORT(parse_port_addr(&arg).ok_or_else(invalid)?),
     "PASV" => FtpCommand::PASV,
-    "RETR" => FtpCommand::RETR(arg),
-    "STOR" => FtpCommand::STOR(arg),
+    "EPRT" => FtpCommand::EPRT(parse_extended_addr(&arg).ok_or_else(invalid)?),
+    "EPSV" => FtpCommand::EPSV(empty_to_some(arg)),
+    "RETR" => FtpCommand::RETR(required(arg)?),
+    "STOR" => FtpCommand::STOR(required(arg)?),
     "ABOR" => FtpCommand::ABOR,
     "QUIT" => FtpCommand::QUIT,
     "SYST" => FtpCommand::SYST,
-    "TYPE" => FtpCommand::TYPE(arg),
-    "RNFR" => FtpCommand::RNFR(arg),
-    "RNTO" => FtpCommand::RNTO(arg),
+    "TYPE" => FtpCommand::TYPE(required(arg)?),
+    "MODE" => FtpCommand::MODE(required(arg)?),
+    "RNFR" => FtpCommand::RNFR(required(arg)?),
+    "RNTO" => FtpCommand::RNTO(required(arg)?),
     "PWD" => FtpCommand::PWD,
-    "CWD" => FtpCommand::CWD(arg),
-    "MKD" => FtpCommand::MKD(arg),
-    "RMD" => FtpCommand::RMD(arg),
+    "CWD" => FtpCommand::CWD(required(arg)?),
+    "MKD" => FtpCommand::MKD(required(arg)?),
+    "RMD" => FtpCommand::RMD(required(arg)?),
     "LIST" => FtpCommand::LIST(empty_to_some(arg)),
-    "REST" => FtpCommand::REST(arg.parse().unwrap()),
-    "DELE" => FtpCommand::DELE(arg),
+    "REST" => FtpCommand::REST(number(&arg)?),
+    "DELE" => FtpCommand::DELE(required(arg)?),
     "STAT" => FtpCommand::STAT(empty_to_some(arg)),
     "STOU" => FtpCommand::STOU,
-    "APPE" => FtpCommand::APPE(arg),
-    "ALLO" => FtpCommand::ALLO(arg.parse().unwrap()),
+    "APPE" => FtpCommand::APPE(required(arg)?),
+    "ALLO" => FtpCommand::ALLO(number(&arg)?),
+    "NOOP" => FtpCommand::NOOP,
     "FEAT" => FtpCommand::FEAT,
     "CDUP" => FtpCommand::CDUP,
-    "MDTM" => FtpCommand::MDTM(arg),
+    "MDTM" => FtpCommand::MDTM(required(arg)?),
+    "SIZE" => FtpCommand::SIZE(required(arg)?),
+    "HASH" => FtpCommand::HASH(required(arg)?),
+    "RANG" => FtpCommand::RANG(required(arg)?),
+    "XCRC" => FtpCommand::XCRC(required(arg)?),
+    "XMD5" => FtpCommand::XMD5(required(arg)?),
+    "XSHA1" => FtpCommand::XSHA1(required(arg)?),
+    "XSHA256" => FtpCommand::XSHA256(required(arg)?),
+    "XSHA512" => FtpCommand::XSHA512(required(arg)?),
+    "MFMT" => {
+      let (time, path) = value_path(&arg)?;
+      FtpCommand::MFMT(time, path)
+    }
+    "MFCT" => {
+      let (time, path) = value_path(&arg)?;
+      FtpCommand::MFCT(time, path)
+    }
+    "MFF" => {
+      let (facts, path) = value_path(&arg)?;
+      FtpCommand::MFF(facts, path)
+    }
+    "SITE" => {
+      let (subcommand, rest) = split_value_path(&arg);
+      match subcommand.to_ascii_uppercase().as_str() {
+        "UTIME" => {
+          let (time, path) = parse_site_utime(&rest).ok_or_else(invalid)?;
+          FtpCommand::MFMT(time, path)
+        }
+        "" => return Err(invalid()),
+        subcommand => {
+          return Err(ParseError::NotImplemented(format!("SITE {}", subcommand)));
+        }
+      }
+    }
     "NLST" => FtpCommand::NLST(empty_to_some(arg)),
-    _ => {
-      println!("Unknown command: {}, Args: {}", cmd, arg);
-      FtpCommand::NOOP
+    "MLSD" => FtpCommand::MLSD(empty_to_some(arg)),
+    "MLST" => FtpCommand::MLST(empty_to_some(arg)),
+    "OPTS" => FtpCommand::OPTS(required(arg)?),
+    "LANG" => FtpCommand::LANG(empty_to_some(arg)),
+    "AUTH" => FtpCommand::AUTH(required(arg)?),
+    "PBSZ" => FtpCommand::PBSZ(required(arg)?),
+    "PROT" => FtpCommand::PROT(required(arg)?),
+    verb if NOT_IMPLEMENTED.contains(&verb) => {
+      return Err(ParseError::NotImplemented(verb.to_string()));
     }
+    _ => return Err(ParseError::Unrecognized(verb)),
+  };
+  Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_command() {
+    assert_eq!(
+      parse_command("user alice"),
+      Ok(FtpCommand::USER("alice".into()))
+    );
+    assert_eq!(
+      parse_command("Retr my  file.txt"),
+      Ok(FtpCommand::RETR("my  file.txt".into()))
+    );
+    assert_eq!(
+      parse_command("PORT 127,0,0,1,4,1"),
+      Ok(FtpCommand::PORT("127.0.0.1:1025".parse().unwrap()))
+    );
+    assert_eq!(parse_command("ALLO 1024 R 128"), Ok(FtpCommand::ALLO(1024)));
+    assert_eq!(parse_command("LIST"), Ok(FtpCommand::LIST(None)));
+
+    let invalid = |verb: &str| Err(ParseError::InvalidArgument(verb.into()));
+    assert_eq!(parse_command("PORT 127,0,0,1,4"), invalid("PORT"));
+    assert_eq!(parse_command("PORT 300,0,0,1,4,1"), invalid("PORT"));
+    assert_eq!(parse_command("REST abc"), invalid("REST"));
+    assert_eq!(parse_command("RETR"), invalid("RETR"));
+    assert_eq!(parse_command("EPRT |3|x|1|"), Ok(FtpCommand::EPRT(None)));
+    assert_eq!(parse_command("EPRT |1|::1|1|"), invalid("EPRT"));
+    assert_eq!(parse_command("EPRT |x|127.0.0.1|1|"), invalid("EPRT"));
+    assert_eq!(parse_command("MFMT 20240101000000"), invalid("MFMT"));
+
+    assert_eq!(parse_command(""), Err(ParseError::Unrecognized("".into())));
+    assert_eq!(
+      parse_command("BOGUS x"),
+      Err(ParseError::Unrecognized("BOGUS".into()))
+    );
+    assert_eq!(
+      parse_command("stru F"),
+      Err(ParseError::NotImplemented("STRU".into()))
+    );
+    assert_eq!(
+      parse_command("SITE CHMOD 755 a"),
+      Err(ParseError::NotImplemented("SITE CHMOD".into()))
+    );
+  }
+
+  #[test]
+  fn test_required_permission() {
+    let permission = |line: &str| parse_command(line).unwrap().required_permission();
+    assert_eq!(permission("RETR a.txt"), Some(Permission::Read));
+    assert_eq!(permission("XSHA256 a.txt"), Some(Permission::Read));
+    assert_eq!(permission("STOR a.txt"), Some(Permission::Write));
+    assert_eq!(permission("STOU"), Some(Permission::Write));
+    assert_eq!(permission("APPE a.txt"), Some(Permission::Write));
+    assert_eq!(
+      permission("MFMT 20240101000000 a.txt"),
+      Some(Permission::Write)
+    );
+    assert_eq!(permission("DELE a.txt"), Some(Permission::Delete));
+    assert_eq!(permission("RMD d"), Some(Permission::Delete));
+    assert_eq!(permission("MKD d"), Some(Permission::Mkdir));
+    assert_eq!(permission("RNFR a.txt"), Some(Permission::Rename));
+    assert_eq!(permission("RNTO b.txt"), Some(Permission::Rename));
+    assert_eq!(permission("LIST"), Some(Permission::List));
+    assert_eq!(permission("SIZE a.txt"), Some(Permission::List));
+    assert_eq!(permission("STAT a.txt"), Some(Permission::List));
+    assert_eq!(permission("STAT"), None);
+    assert_eq!(permission("CWD d"), None);
+    assert_eq!(permission("PWD"), None);
   }
 }
@@ -0,0 +1,31 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use crate::lib::acl::AclRuleConfig;
+
+/// Server config file (TOML) passed with `--config`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+  /// Group name to member usernames.
+  #[serde(default)]
+  pub groups: HashMap<String, Vec<String>>,
+  #[serde(default)]
+  pub acl: Vec<AclRuleConfig>,
+  /// Globs over paths below the served folder, e.g. `/releases/*.tar.gz`, of
+  /// archives that can be entered like read-only directories.
+  #[serde(default)]
+  pub archives: Vec<String>,
+  /// Answer `RETR somedir.zip` and `RETR somedir.tar.gz` with an archive of
+  /// `somedir` generated on the fly.
+  #[serde(default)]
+  pub archive_dirs: bool,
+}
+
+impl Config {
+  pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    Ok(toml::from_str(&content)?)
+  }
+}
@@ -1,243 +1,389 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, Utc};
 use std::error::Error;
-use std::fs::{self, OpenOptions};
-use std::io::{Read, Seek, Write};
-use std::path::{Path, PathBuf};
-use std::str::FromStr;
-use std::{net::SocketAddr, sync::Arc};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
 use uuid::Uuid;
 
 use async_trait::async_trait;
 
+use crate::lib::ascii;
+use crate::lib::auth::{self, Account};
+use crate::lib::block::{self, END_OF_FILE, RESTART_MARKER};
+use crate::lib::charset::Charset;
+use crate::lib::hash::{self, HashAlgorithm};
+use crate::lib::mfxx::{self, ChangeError};
+use crate::lib::mlsx;
+use crate::lib::permission::{Permission, Permissions};
 use crate::lib::server::Server;
 use crate::lib::session::*;
+use crate::lib::storage::{self, FileReader, Metadata, PackFormat, PackMember, StorageBackend};
+use crate::lib::stream::{BoxedStream, ControlWriter};
+use crate::lib::tls::DataTls;
 use crate::lib::user::*;
 
 #[async_trait]
 pub trait FtpServer {
   async fn list(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
   ) -> Result<(), Box<dyn Error>>;
   async fn retrieve(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn store(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn make_dir(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn remove_dir(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn delete(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn cwd(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn pwd(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn set_type(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     type_: String,
   ) -> Result<(), Box<dyn Error>>;
+  async fn set_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    mode: String,
+  ) -> Result<(), Box<dyn Error>>;
   async fn passive_mode(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn port_mode(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     port_addr: SocketAddr,
   ) -> Result<(), Box<dyn Error>>;
+  async fn extended_port_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    port_addr: Option<SocketAddr>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn extended_passive_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    arg: Option<String>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn machine_list(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    optional_dir: Option<String>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn size(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    file_name: String,
+  ) -> Result<(), Box<dyn Error>>;
+  /// `MFF`, and `MFMT`/`MFCT` with their time as a `modify`/`create` fact.
+  async fn modify_facts(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    facts: String,
+    file_name: String,
+  ) -> Result<(), Box<dyn Error>>;
+  /// `HASH` when `algorithm` is `None`, otherwise one of the `X*` commands.
+  async fn hash(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    file_name: String,
+    algorithm: Option<HashAlgorithm>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn range(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    range: String,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn machine_status(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    optional_path: Option<String>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn options(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    option: String,
+  ) -> Result<(), Box<dyn Error>>;
   async fn quit(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn noop(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn user(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     username: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn pass(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     password: String,
   ) -> Result<(), Box<dyn Error>>;
 
   async fn abort(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn system_info(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn language(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
+    language: Option<String>,
   ) -> Result<(), Box<dyn Error>>;
   async fn rename_from(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn rename_to(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn restart(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     offset: u64,
   ) -> Result<(), Box<dyn Error>>;
   async fn status(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_path: Option<String>,
   ) -> Result<(), Box<dyn Error>>;
   async fn store_unique(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn append(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn allocate(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     size: u64,
   ) -> Result<(), Box<dyn Error>>;
   async fn feat(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn cd_up(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>>;
   async fn get_modify_timestamp(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
   async fn name_list(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
   ) -> Result<(), Box<dyn Error>>;
+  async fn protection_buffer_size(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    size: String,
+  ) -> Result<(), Box<dyn Error>>;
+  async fn protection_level(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    level: String,
+  ) -> Result<(), Box<dyn Error>>;
+}
+
+/// Wraps a freshly opened data connection in TLS when `PROT P` is in effect.
+async fn wrap_data_stream(stream: TcpStream, tls: Option<DataTls>) -> Option<BoxedStream> {
+  match tls {
+    Some(tls) => match tls.accept(stream).await {
+      Ok(stream) => Some(Box::new(stream)),
+      Err(e) => {
+        println!("Data connection TLS handshake failed: {}", e);
+        None
+      }
+    },
+    None => Some(Box::new(stream)),
+  }
 }
 
 #[async_trait]
 trait FtpHelper {
   async fn list_files(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
-    name_only: bool,
+    format: ListFormat,
   ) -> Result<(), Box<dyn Error>>;
 
   async fn store_file(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>>;
+
+  async fn open_passive(&self, user: Arc<Mutex<User>>) -> Result<SocketAddr, Box<dyn Error>>;
+
+  fn facts_of(&self, user: &User, metadata: &Metadata, virtual_path: &str) -> String;
+
+  async fn pack_dir(
+    &self,
+    account: Option<&Account>,
+    charset: Charset,
+    root: &Path,
+    virtual_path: &str,
+  ) -> Option<FileReader>;
 }
 
 #[async_trait]
 impl FtpHelper for Server {
   async fn list_files(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
-    name_only: bool,
+    format: ListFormat,
   ) -> Result<(), Box<dyn Error>> {
-    let mut control = control.lock().await;
     let user = user.lock().await;
-    let path = match optional_dir {
-      Some(path) => Path::new(&self.root).join(&user.pwd()).join(path),
-      None => Path::new(&self.root).join(&user.pwd()),
+    let mut control = control.lock().await;
+    let dir = optional_dir.as_deref().unwrap_or(".");
+    let path = match user.resolve(dir).ok() {
+      Some(path) => path,
+      None => {
+        control.write_all(b"550 Permission denied.\r\n").await?;
+        return Ok(());
+      }
+    };
+    let metadata = match self.storage.metadata(&path).await {
+      Ok(metadata) => metadata,
+      Err(_) => {
+        control
+          .write_all(b"550 No such file or directory.\r\n")
+          .await?;
+        return Ok(());
+      }
     };
-    if !path.exists() {
-      control
-        .write_all(b"550 No such file or directory.\r\n")
-        .await?;
-      return Ok(());
-    }
-    let path = path.canonicalize()?;
-    if !path.starts_with(&self.root) {
-      control.write_all(b"550 Permission denied.\r\n").await?;
-      return Ok(());
-    }
 
-    let list = get_list_lines(&path, name_only)?;
+    let storage = &*self.storage;
+    let list = match format {
+      ListFormat::Long => get_list_lines(storage, &path, &metadata, false, user.charset()).await?,
+      ListFormat::NameOnly => {
+        get_list_lines(storage, &path, &metadata, true, user.charset()).await?
+      }
+      ListFormat::Machine => {
+        if !metadata.is_dir && !metadata.archive {
+          control.write_all(b"501 Not a directory.\r\n").await?;
+          return Ok(());
+        }
+        let mut list = String::new();
+        for entry in storage.list(&path).await? {
+          let name = user.charset().file_name(&entry.name);
+          let virtual_path = user.virtual_path(&format!("{}/{}", dir, name));
+          let facts = self.facts_of(&user, &entry.metadata, &virtual_path);
+          list.push_str(&format!("{} {}\r\n", facts, name));
+        }
+        list
+      }
+    };
 
     let session = user.get_session()?;
     let mut session = session.lock().await;
-    let data_stream = session.get_stream();
-    let mut data_stream = data_stream.lock().await;
 
     control
       .write_all(b"150 Opening ASCII mode data connection for file list\r\n")
       .await?;
-    data_stream.write_all(list.as_bytes()).await?;
-    data_stream.shutdown().await?;
+    session.start(dir);
+    let data_stream = session.get_stream().await?;
+    let mut data_stream = data_stream.lock().await;
+    let list = user.encode(&list);
+    if user.mode == TransmissionMode::Block {
+      block::write_block(&mut *data_stream, 0, &list).await?;
+      block::write_block(&mut *data_stream, END_OF_FILE, &[]).await?;
+      data_stream.flush().await?;
+    } else {
+      data_stream.write_all(&list).await?;
+      data_stream.shutdown().await?;
+    }
     session.finished = true;
     control.write_all(b"226 Transfer complete.\r\n").await?;
     Ok(())
@@ -245,25 +391,55 @@ impl FtpHelper for Server {
 
   async fn store_file(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
-    let (target_path, mut offset) = {
+    let (target_path, restart, root, quota, used, ascii, block_mode) = {
       let user = user.lock().await;
-      let path = Path::new(&self.root).join(&user.pwd()).join(&file_name);
+      let path = user.resolve(&file_name).ok();
       let session = user.get_session()?;
       let mut session = session.lock().await;
-      session.file_name = file_name.clone();
 
-      (path, session.offset)
+      (
+        path,
+        session.start(&file_name),
+        user.root().to_path_buf(),
+        user.account.as_ref().and_then(|a| a.quota),
+        user.used_space,
+        matches!(user.trans_type, TransferType::ASCII),
+        user.mode == TransmissionMode::Block,
+      )
+    };
+    let mut offset = restart;
+
+    let target_path = match target_path {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
     };
 
-    if !target_path.starts_with(&self.root) {
+    // Walking the whole root lists every object on S3, so it only happens
+    // once per session.
+    let used = match (quota, used) {
+      (Some(_), None) => Some(dir_size(&*self.storage, &root).await),
+      (_, used) => used,
+    };
+    user.lock().await.used_space = used;
+    let mut remaining = quota
+      .zip(used)
+      .map(|(quota, used)| quota.saturating_sub(used));
+    if remaining == Some(0) {
       control
         .lock()
         .await
-        .write_all(b"550 Permission denied.\r\n")
+        .write_all(b"552 Exceeded storage allocation.\r\n")
         .await?;
       return Ok(());
     }
@@ -274,7 +450,8 @@ impl FtpHelper for Server {
         .await
         .write_all(
           format!(
-            "150 Opening BINARY mode data connection for {}.\r\n",
+            "150 Opening {} mode data connection for {}.\r\n",
+            if ascii { "ASCII" } else { "BINARY" },
             file_name
           )
           .as_bytes(),
@@ -282,71 +459,147 @@ impl FtpHelper for Server {
         .await?;
     }
 
-    let mut file = if target_path.exists() {
-      let meta = target_path.metadata()?;
-      if meta.is_dir() {
-        control
-          .lock()
-          .await
-          .write_all(b"550 Permission denied, the path is a directory.\r\n")
-          .await?;
-        return Ok(());
-      }
-      if offset == 0 {
-        control
-          .lock()
-          .await
-          .write_all(b"550 Permission denied, the file exists.\r\n")
-          .await?;
-        return Ok(());
+    // Bytes of the transfer the client does not send again, which restart
+    // markers count from.
+    let mut resumed = 0;
+    let mut existing = 0;
+    let mut file = match self.storage.metadata(&target_path).await {
+      Ok(meta) => {
+        existing = meta.len;
+        if meta.is_dir {
+          control
+            .lock()
+            .await
+            .write_all(b"550 Permission denied, the path is a directory.\r\n")
+            .await?;
+          return Ok(());
+        }
+        if offset == 0 {
+          control
+            .lock()
+            .await
+            .write_all(b"550 Permission denied, the file exists.\r\n")
+            .await?;
+          return Ok(());
+        }
+        if ascii {
+          // The offset counts the converted bytes the client has sent.
+          let file = self.storage.open_read(&target_path, 0).await?;
+          offset = ascii::restart_position(file, offset).await?;
+        }
+        if offset > meta.len {
+          offset = meta.len;
+        }
+        resumed = if ascii {
+          let file = self.storage.open_read(&target_path, 0).await?;
+          restart.min(ascii::network_size(file).await?)
+        } else {
+          offset
+        };
+        self.storage.open_write(&target_path, Some(offset)).await?
       }
-      if offset > meta.len() {
-        offset = meta.len();
+      Err(_) => {
+        // A new file starts from scratch whatever `REST` said.
+        offset = 0;
+        self.storage.open_write(&target_path, None).await?
       }
-      let mut file = OpenOptions::new().write(true).open(target_path)?;
-      file.seek(std::io::SeekFrom::Start(offset))?;
-      file
-    } else {
-      fs::File::create(target_path)?
     };
 
+    let mut decoder = ascii.then(ascii::Decoder::new);
+    let mut converted = Vec::new();
+    let mut received = 0;
+    let mut written = 0;
+    let mut exceeded = false;
+    let mut truncated = false;
+    // Only the data connection stays locked during the transfer, so that
+    // `STAT` can report the progress meanwhile.
+    let session = {
+      let mut user = user.lock().await;
+      // Set again at the end, an upload failing half way leaves the next one
+      // to count again.
+      user.used_space = None;
+      user.get_session()?
+    };
+    let data_stream = session.lock().await.get_stream().await?;
+    let mut data_stream = data_stream.lock().await;
     loop {
-      let user = user.lock().await;
-      let session = user.get_session()?;
-      let mut session = session.lock().await;
-
-      if session.aborted {
-        break;
-      }
-
-      let data_stream = session.get_stream();
-      let mut data_stream = data_stream.lock().await;
-
       let mut buf = vec![0; 1024];
-      let n = data_stream.read(&mut buf).await?;
-
-      if n == 0 {
+      let (n, end) = if block_mode {
+        match block::read_block(&mut *data_stream).await? {
+          Some(block) if block.is_restart_marker() => {
+            // Tell the client where to restart from, in terms of `REST`.
+            let mark = format!(
+              "110 MARK {} = {}\r\n",
+              String::from_utf8_lossy(&block.data),
+              resumed + received
+            );
+            control.lock().await.write_all(mark.as_bytes()).await?;
+            continue;
+          }
+          Some(block) => {
+            let end = block.is_end_of_file();
+            buf = block.data;
+            (buf.len(), end)
+          }
+          None => {
+            truncated = true;
+            break;
+          }
+        }
+      } else {
+        let n = data_stream.read(&mut buf).await?;
+        (n, n == 0)
+      };
+      received += n as u64;
+
+      let data = match decoder.as_mut() {
+        Some(decoder) => {
+          converted.clear();
+          decoder.decode(&buf[..n], &mut converted);
+          if end {
+            decoder.finish(&mut converted);
+          }
+          &converted[..]
+        }
+        None => &buf[..n],
+      };
+      if let Some(left) = remaining {
+        if (data.len() as u64) > left {
+          exceeded = true;
+          break;
+        }
+        remaining = Some(left - data.len() as u64);
+      }
+      file.write_all(data).await?;
+      written += data.len() as u64;
+      session.lock().await.finished_size += n as u64;
+      if end {
         break;
       }
-      file.write_all(&buf[..n])?;
-      session.finished_size += n as u64;
     }
-
-    let user = user.lock().await;
-    let session = user.get_session()?;
-    let mut session = session.lock().await;
-
-    let data_stream = session.get_stream();
-    let mut data_stream = data_stream.lock().await;
-    data_stream.shutdown().await?;
-    if session.aborted {
+    file.shutdown().await?;
+    // Rewriting after `REST` only counts what grows past the old end.
+    let grown = (offset + written).saturating_sub(existing);
+    user.lock().await.used_space = used.map(|used| used + grown);
+
+    // In block mode the data connection stays open for the next transfer.
+    if !block_mode || exceeded {
+      data_stream.shutdown().await?;
+    }
+    if exceeded {
+      control
+        .lock()
+        .await
+        .write_all(b"552 Transfer aborted, exceeded storage allocation.\r\n")
+        .await?;
+    } else if truncated {
       control
         .lock()
         .await
-        .write_all(b"226 Connection closed; transfer aborted.\r\n")
+        .write_all(b"426 Connection closed before the end of file.\r\n")
         .await?;
     } else {
-      session.finished = true;
+      session.lock().await.finished = true;
       control
         .lock()
         .await
@@ -355,37 +608,160 @@ impl FtpHelper for Server {
     }
     Ok(())
   }
+
+  /// Opens a passive data listener on the address the client reached the
+  /// control connection on, and starts waiting for the client in the
+  /// background.
+  async fn open_passive(&self, user: Arc<Mutex<User>>) -> Result<SocketAddr, Box<dyn Error>> {
+    let mut user = user.lock().await;
+    let listener = self.generate_pasv_addr(user.local_addr.ip()).await?;
+    let listen_addr = listener.local_addr()?;
+
+    let (sender, receiver) = oneshot::channel::<BoxedStream>();
+    user.set_new_session(TransferSession::new(TransferMode::Passive, receiver));
+    let tls = self.data_tls(&user);
+
+    tokio::spawn(async move {
+      let (stream, _) = match listener.accept().await {
+        Ok((s, addr)) => (s, addr),
+        Err(e) => {
+          println!("Listen pasv error: {}", e);
+          return;
+        }
+      };
+      if let Some(stream) = wrap_data_stream(stream, tls).await {
+        let _ = sender.send(stream);
+      }
+    });
+    Ok(listen_addr)
+  }
+
+  /// `MLSD`/`MLST` facts of a file, with the `perm` fact reflecting what the
+  /// user's permissions and the access rules allow on it.
+  fn facts_of(&self, user: &User, metadata: &Metadata, virtual_path: &str) -> String {
+    let allows = |permission| {
+      user
+        .account
+        .as_ref()
+        .is_some_and(|account| self.acl.allows(account, virtual_path, permission))
+    };
+    mlsx::facts(metadata, &user.mlst_facts, allows)
+  }
+
+  /// The archive generated for `RETR somedir.zip` or `somedir.tar.gz` when
+  /// only `somedir` exists, leaving out whatever the user may not list or
+  /// read. Takes what it needs of the user rather than the user itself, so
+  /// that `STAT` and `ABOR` are not blocked during the walk.
+  async fn pack_dir(
+    &self,
+    account: Option<&Account>,
+    charset: Charset,
+    root: &Path,
+    virtual_path: &str,
+  ) -> Option<FileReader> {
+    if !self.archive_dirs {
+      return None;
+    }
+    let (format, virtual_dir) = PackFormat::strip(virtual_path)?;
+    if virtual_dir.ends_with('/') {
+      return None;
+    }
+    let allows = |path: &str, permission| {
+      account.is_some_and(|account| self.acl.allows(account, path, permission))
+    };
+    // Every member goes through the same jail as the user's own paths.
+    let resolve = |path: &str| {
+      self
+        .storage
+        .resolve(root, root.join(path.trim_start_matches('/')))
+        .ok()
+    };
+    let path = resolve(virtual_dir)?;
+    let metadata = self.storage.metadata(&path).await.ok()?;
+    if !metadata.is_dir || !allows(virtual_dir, Permission::List) {
+      return None;
+    }
+
+    // Members are named after the directory, like in `tar -czf dir.tar.gz dir`.
+    let top = PackMember {
+      path,
+      name: virtual_dir.rsplit('/').next()?.to_string(),
+      metadata,
+    };
+    let mut members = Vec::new();
+    let mut dirs = vec![(top, virtual_dir.to_string(), 0)];
+    while let Some((dir, virtual_dir, depth)) = dirs.pop() {
+      let (path, name) = (dir.path.clone(), dir.name.clone());
+      members.push(dir);
+      if depth == PACK_DEPTH_LIMIT {
+        continue;
+      }
+      let mut entries = self.storage.list(&path).await.unwrap_or_default();
+      entries.sort_by(|a, b| a.name.cmp(&b.name));
+      let mut subdirs = Vec::new();
+      for entry in entries {
+        let entry_name = charset.file_name(&entry.name);
+        let virtual_path = format!("{}/{}", virtual_dir, entry_name);
+        let Some(path) = resolve(&virtual_path) else {
+          continue;
+        };
+        let member = PackMember {
+          path,
+          name: format!("{}/{}", name, entry_name),
+          metadata: entry.metadata,
+        };
+        if member.metadata.is_dir {
+          if allows(&virtual_path, Permission::List) {
+            subdirs.push((member, virtual_path, depth + 1));
+          }
+        } else if allows(&virtual_path, Permission::Read) {
+          // Listings do not follow symlinks, the member takes the size of
+          // the file it points to and symlinks to directories are left out.
+          match self.storage.metadata(&member.path).await {
+            Ok(metadata) if !metadata.is_dir => members.push(PackMember { metadata, ..member }),
+            _ => {}
+          }
+        }
+      }
+      dirs.extend(subdirs.into_iter().rev());
+    }
+    Some(storage::pack(self.storage.clone(), format, members))
+  }
+}
+
+/// Output of the listing commands.
+enum ListFormat {
+  /// `LIST`, in the style of `ls -l`.
+  Long,
+  /// `NLST`, one name per line.
+  NameOnly,
+  /// `MLSD`, with facts as in RFC 3659.
+  Machine,
 }
 
-fn file_path_to_list_item(path: &PathBuf, name_only: bool) -> Result<String, Box<dyn Error>> {
+fn file_to_list_item(
+  file_name: &str,
+  metadata: &Metadata,
+  name_only: bool,
+) -> Result<String, Box<dyn Error>> {
   // https://files.stairways.com/other/ftp-list-specs-info.txt
   // http://cr.yp.to/ftp/list/binls.html
-  let metadata = fs::metadata(&path)?;
-  let file_name = match path.file_name() {
-    Some(name) => match name.to_str() {
-      Some(name) => name,
-      None => {
-        return Err("Error: file name is not valid UTF-8.".into());
-      }
-    },
-    None => {
-      return Err("Error: file name is None.".into());
-    }
-  };
   if name_only {
     return Ok(format!("{}\r\n", file_name).to_string());
   }
-  let file_size = format!("{:>13}", metadata.len());
-  let file_type = if metadata.is_dir() { "d" } else { "-" };
+  let file_size = format!("{:>13}", metadata.len);
+  let file_type = if metadata.is_dir { "d" } else { "-" };
+  // Directories of object stores have no time of their own.
   let file_time = metadata
-    .modified()?
+    .modified
+    .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
     .duration_since(std::time::SystemTime::UNIX_EPOCH)?;
   let file_time = DateTime::from_timestamp(file_time.as_secs() as i64, 0)
     .ok_or("Error: failed to convert timestamp.")?
     .with_timezone(&Local)
     .format("%b %d %H:%M")
     .to_string();
-  let permission = if metadata.is_dir() {
+  let permission = if metadata.is_dir {
     "rwxr-xr-x"
   } else {
     "rw-r--r--"
@@ -399,16 +775,51 @@ fn file_path_to_list_item(path: &PathBuf, name_only: bool) -> Result<String, Box
   )
 }
 
-fn get_list_lines(path: &PathBuf, name_only: bool) -> Result<String, Box<dyn Error>> {
+/// Bytes sent between two restart markers in block mode.
+const RESTART_MARKER_INTERVAL: u64 = 1024 * 1024;
+
+/// Directories nested deeper than this are packed without their entries.
+const PACK_DEPTH_LIMIT: usize = 64;
+
+/// Files above this size are not scanned to answer `SIZE` in ASCII mode.
+const ASCII_SIZE_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Total size of the files below `path`, without following symlinks.
+async fn dir_size(storage: &dyn StorageBackend, path: &Path) -> u64 {
+  let mut size = 0;
+  let mut dirs = vec![path.to_path_buf()];
+  while let Some(dir) = dirs.pop() {
+    for entry in storage.list(&dir).await.unwrap_or_default() {
+      if entry.metadata.is_dir {
+        dirs.push(dir.join(&entry.name));
+      } else {
+        size += entry.metadata.len;
+      }
+    }
+  }
+  size
+}
+
+/// `LIST`/`NLST` lines of `path`, which has the given `metadata`.
+async fn get_list_lines(
+  storage: &dyn StorageBackend,
+  path: &Path,
+  metadata: &Metadata,
+  name_only: bool,
+  charset: Charset,
+) -> Result<String, Box<dyn Error>> {
   let mut list = String::new();
-  if path.is_dir() {
-    let mut files = fs::read_dir(&path)?;
-    while let Some(file) = files.next() {
-      let file = file?;
-      list.push_str(file_path_to_list_item(&file.path(), name_only)?.as_str());
+  if metadata.is_dir || metadata.archive {
+    for entry in storage.list(path).await? {
+      let name = charset.file_name(&entry.name);
+      list.push_str(file_to_list_item(&name, &entry.metadata, name_only)?.as_str());
     }
   } else {
-    list.push_str(file_path_to_list_item(path, name_only)?.as_str());
+    let name = match path.file_name() {
+      Some(name) => charset.file_name(name),
+      None => return Err("Error: file name is None.".into()),
+    };
+    list.push_str(file_to_list_item(&name, metadata, name_only)?.as_str());
   }
   Ok(list)
 }
@@ -417,47 +828,82 @@ fn get_list_lines(path: &PathBuf, name_only: bool) -> Result<String, Box<dyn Err
 impl FtpServer for Server {
   async fn list(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
   ) -> Result<(), Box<dyn Error>> {
-    self.list_files(control, user, optional_dir, false).await
+    self
+      .list_files(control, user, optional_dir, ListFormat::Long)
+      .await
   }
 
   async fn name_list(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_dir: Option<String>,
   ) -> Result<(), Box<dyn Error>> {
-    self.list_files(control, user, optional_dir, true).await
+    self
+      .list_files(control, user, optional_dir, ListFormat::NameOnly)
+      .await
   }
 
   async fn retrieve(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
-    let (path, offset) = {
+    let (path, offset, ascii, block_mode) = {
       let user = user.lock().await;
 
-      let path = Path::new(&self.root).join(&user.pwd()).join(&file_name);
+      let path = user.resolve(&file_name).ok();
       let session = user.get_session()?;
       let mut session = session.lock().await;
-      session.file_name = file_name.clone();
 
-      (path, session.offset)
+      (
+        path,
+        session.start(&file_name),
+        matches!(user.trans_type, TransferType::ASCII),
+        user.mode == TransmissionMode::Block,
+      )
     };
 
-    // Path join self.root, current_user.pwd, file_name
-    if !Path::exists(&path) {
-      control
-        .lock()
-        .await
-        .write_all(b"550 File not found.\r\n")
-        .await?;
-    }
+    let metadata = match &path {
+      Some(path) => self.storage.metadata(path).await.ok(),
+      None => None,
+    };
+    // Without such a file `somedir.zip` may stand for an archive of `somedir`,
+    // whose size is not known before it is generated.
+    let packed = match (&path, &metadata) {
+      (Some(_), None) => {
+        let (account, charset, root, virtual_path) = {
+          let user = user.lock().await;
+          (
+            user.account.clone(),
+            user.charset(),
+            user.root().to_path_buf(),
+            user.virtual_path(&file_name),
+          )
+        };
+        self
+          .pack_dir(account.as_ref(), charset, &root, &virtual_path)
+          .await
+      }
+      _ => None,
+    };
+    let (path, file_size) = match (path, metadata) {
+      (Some(path), Some(metadata)) if !metadata.is_dir => (path, Some(metadata.len)),
+      (Some(path), None) if packed.is_some() => (path, None),
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 File not found.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
 
     {
       control
@@ -465,7 +911,8 @@ impl FtpServer for Server {
         .await
         .write_all(
           format!(
-            "150 Opening BINARY mode data connection for {}.\r\n",
+            "150 Opening {} mode data connection for {}.\r\n",
+            if ascii { "ASCII" } else { "BINARY" },
             file_name
           )
           .as_bytes(),
@@ -473,66 +920,89 @@ impl FtpServer for Server {
         .await?;
     }
 
-    let mut file = fs::File::open(path)?;
-    if offset > 0 {
-      let meta = file.metadata()?;
-      let file_size = meta.len();
-      if offset >= file_size {
-        control
-          .lock()
-          .await
-          .write_all(b"550 Offset out of range.\r\n")
-          .await?;
-        return Ok(());
-      }
-      file.seek(std::io::SeekFrom::Start(offset))?;
-    }
-    loop {
+    {
       let user = user.lock().await;
       let session = user.get_session()?;
-      let mut session = session.lock().await;
-      if session.aborted {
-        break;
+      session.lock().await.total_size = file_size.unwrap_or(0);
+    }
+    // In ASCII mode the offset counts converted bytes, so the encoder skips
+    // them instead of seeking.
+    let mut encoder = ascii.then(|| ascii::Encoder::new(offset));
+    let mut converted = Vec::new();
+    if offset > 0 && !ascii && file_size.is_some_and(|size| offset >= size) {
+      control
+        .lock()
+        .await
+        .write_all(b"550 Offset out of range.\r\n")
+        .await?;
+      return Ok(());
+    }
+    let start = if ascii { 0 } else { offset };
+    let mut file = match packed {
+      // The same directory always gives the same archive, so a restart
+      // skips what was sent before.
+      Some(mut archive) => {
+        tokio::io::copy(&mut (&mut archive).take(start), &mut tokio::io::sink()).await?;
+        archive
       }
-      let data_stream = session.get_stream();
-      let mut data_stream = data_stream.lock().await;
+      None => self.storage.open_read(&path, start).await?,
+    };
+    let mut sent = 0;
+    let mut next_marker = RESTART_MARKER_INTERVAL;
+    // Only the data connection stays locked during the transfer, so that
+    // `STAT` can report the progress meanwhile.
+    let session = user.lock().await.get_session()?;
+    let data_stream = session.lock().await.get_stream().await?;
+    let mut data_stream = data_stream.lock().await;
+    loop {
       let mut buf = vec![0u8; 1024];
-      let n = file.read(&mut buf)?;
+      let n = file.read(&mut buf).await?;
       if n == 0 {
         break;
       }
-      data_stream.write_all(&buf[..n]).await?;
-      session.finished_size += n as u64;
+      let data = match encoder.as_mut() {
+        Some(encoder) => {
+          converted.clear();
+          encoder.encode(&buf[..n], &mut converted);
+          &converted[..]
+        }
+        None => &buf[..n],
+      };
+      if block_mode {
+        block::write_block(&mut *data_stream, 0, data).await?;
+        sent += data.len() as u64;
+        if sent >= next_marker {
+          // The marker is the offset to pass to `REST` for resuming here.
+          let marker = (offset + sent).to_string();
+          block::write_block(&mut *data_stream, RESTART_MARKER, marker.as_bytes()).await?;
+          next_marker += RESTART_MARKER_INTERVAL;
+        }
+      } else {
+        data_stream.write_all(data).await?;
+      }
+      session.lock().await.finished_size += n as u64;
     }
 
-    let user = user.lock().await;
-    let session = user.get_session()?;
-    let mut session = session.lock().await;
-
-    let data_stream = session.get_stream();
-    let mut data_stream = data_stream.lock().await;
-    data_stream.shutdown().await?;
-
-    if session.aborted {
-      control
-        .lock()
-        .await
-        .write_all(b"226 Connection closed; transfer aborted.\r\n")
-        .await?;
+    // In block mode the data connection stays open for the next transfer.
+    if block_mode {
+      block::write_block(&mut *data_stream, END_OF_FILE, &[]).await?;
+      data_stream.flush().await?;
     } else {
-      session.finished = true;
-      control
-        .lock()
-        .await
-        .write_all(b"226 Transfer complete.\r\n")
-        .await?;
+      data_stream.shutdown().await?;
     }
+
+    session.lock().await.finished = true;
+    control
+      .lock()
+      .await
+      .write_all(b"226 Transfer complete.\r\n")
+      .await?;
     Ok(())
   }
 
   async fn store(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
@@ -541,13 +1011,23 @@ impl FtpServer for Server {
 
   async fn make_dir(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>> {
     let user = user.lock().await;
-    // let parts = current_user.pwd.split("/").collect();
-    match fs::create_dir(Path::new(&self.root).join(&user.pwd()).join(&dir_name)) {
+    let path = match user.resolve(&dir_name).ok() {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    match self.storage.create_dir(&path).await {
       Ok(_) => {
         control
           .lock()
@@ -569,32 +1049,22 @@ impl FtpServer for Server {
 
   async fn remove_dir(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>> {
     let user = user.lock().await;
-    match Path::new(&self.root)
-      .join(&user.pwd())
-      .join(&dir_name)
-      .canonicalize()
-    {
-      Ok(new_path) => {
-        if !new_path.starts_with(&self.root) {
-          control
-            .lock()
-            .await
-            .write_all(b"550 Permission denied.\r\n")
-            .await?;
-        }
-        if !new_path.exists() {
+    match user.resolve(&dir_name).ok() {
+      Some(new_path) => {
+        if !self.storage.exists(&new_path).await {
           control
             .lock()
             .await
             .write_all(b"553 Not found.\r\n")
             .await?;
+          return Ok(());
         }
-        if let Ok(_) = fs::remove_dir(new_path) {
+        if self.storage.remove_dir(&new_path).await.is_ok() {
           control
             .lock()
             .await
@@ -608,12 +1078,11 @@ impl FtpServer for Server {
             .await?;
         }
       }
-      Err(e) => {
-        println!("Error: {:?}", e);
+      None => {
         control
           .lock()
           .await
-          .write_all(b"550 Path error.\r\n")
+          .write_all(b"550 Permission denied.\r\n")
           .await?;
       }
     }
@@ -622,13 +1091,23 @@ impl FtpServer for Server {
 
   async fn delete(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
-    let user = user.lock().await;
-    let path = Path::new(&self.root).join(&user.pwd()).join(&file_name);
-    if !path.exists() {
+    let mut user = user.lock().await;
+    let path = match user.resolve(&file_name).ok() {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    if !self.storage.exists(&path).await {
       control
         .lock()
         .await
@@ -636,16 +1115,17 @@ impl FtpServer for Server {
         .await?;
       return Ok(());
     }
-    if !path.starts_with(&self.root) {
-      control
-        .lock()
+    let len = match user.used_space {
+      Some(_) => self
+        .storage
+        .metadata(&path)
         .await
-        .write_all(b"550 Permission denied.\r\n")
-        .await?;
-      return Ok(());
-    }
-    match fs::remove_file(path) {
+        .map_or(0, |meta| meta.len),
+      None => 0,
+    };
+    match self.storage.remove_file(&path).await {
       Ok(_) => {
+        user.used_space = user.used_space.map(|used| used.saturating_sub(len));
         control
           .lock()
           .await
@@ -666,12 +1146,12 @@ impl FtpServer for Server {
 
   async fn cwd(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     dir_name: String,
   ) -> Result<(), Box<dyn Error>> {
     let mut user = user.lock().await;
-    user.cwd(&dir_name)?;
+    user.cwd(&dir_name).await?;
     control
       .lock()
       .await
@@ -682,7 +1162,7 @@ impl FtpServer for Server {
 
   async fn pwd(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     let user = user.lock().await;
@@ -702,12 +1182,16 @@ impl FtpServer for Server {
 
   async fn set_type(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     type_: String,
   ) -> Result<(), Box<dyn Error>> {
-    match type_.to_uppercase().as_str() {
-      "A" => {
+    // Only the default format control and byte size are supported, e.g.
+    // `TYPE A N` and `TYPE L 8`.
+    let params = type_.to_uppercase();
+    let params = params.split_whitespace().collect::<Vec<_>>();
+    match params.as_slice() {
+      ["A"] | ["A", "N"] => {
         user.lock().await.trans_type = TransferType::ASCII;
         control
           .lock()
@@ -715,7 +1199,7 @@ impl FtpServer for Server {
           .write_all(b"200 Type set to ASCII.\r\n")
           .await?;
       }
-      "I" => {
+      ["I"] | ["L", "8"] => {
         user.lock().await.trans_type = TransferType::Binary;
         control
           .lock()
@@ -734,16 +1218,64 @@ impl FtpServer for Server {
     Ok(())
   }
 
+  async fn set_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    mode: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let (mode, name) = match mode.to_uppercase().as_str() {
+      "S" => (TransmissionMode::Stream, "S"),
+      "B" => (TransmissionMode::Block, "B"),
+      "Z" => (TransmissionMode::Deflate, "Z"),
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"504 Command not implemented for that parameter.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    {
+      let mut user = user.lock().await;
+      user.mode = mode;
+      // A data connection opened before MODE is not in use yet either.
+      let level = (mode == TransmissionMode::Deflate).then_some(user.deflate_level);
+      if let Some(session) = user.session.clone() {
+        session.lock().await.deflate_level = level;
+      }
+    }
+    control
+      .lock()
+      .await
+      .write_all(format!("200 Mode set to {}.\r\n", name).as_bytes())
+      .await?;
+    Ok(())
+  }
+
   async fn passive_mode(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
-    let cloned = user.clone();
-    let listener = self.generate_pasv_addr().await?;
-    let listen_addr = listener.local_addr()?;
-    let ip = listen_addr.ip().to_string().replace(".", ",");
-    let port = listen_addr.port();
+    // The 227 reply can only carry an IPv4 address.
+    let ip = match user.lock().await.local_addr.ip() {
+      IpAddr::V4(ip) => Some(ip),
+      IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+    };
+    let ip = match ip {
+      Some(ip) => ip,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"425 Use EPSV on IPv6 connections.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    let port = self.open_passive(user).await?.port();
 
     control
       .lock()
@@ -751,57 +1283,124 @@ impl FtpServer for Server {
       .write_all(
         format!(
           "227 Entering Passive Mode ({},{},{})\r\n",
-          ip,
+          ip.to_string().replace('.', ","),
           port / 256,
           port % 256,
         )
         .as_bytes(),
       )
       .await?;
-    // let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+    Ok(())
+  }
 
-    tokio::spawn(async move {
-      let (stream, _) = match listener.accept().await {
-        Ok((s, addr)) => (s, addr),
-        Err(e) => {
-          println!("Listen pasv error: {}", e);
-          return;
-        }
-      };
-      cloned
-        .lock()
-        .await
-        .set_new_session(TransferSession::new(TransferMode::Passive(Arc::new(
-          Mutex::new(stream),
-        ))));
-    });
+  async fn extended_port_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    port_addr: Option<SocketAddr>,
+  ) -> Result<(), Box<dyn Error>> {
+    let local_addr = user.lock().await.local_addr;
+    match port_addr {
+      Some(port_addr) if port_addr.is_ipv4() == local_addr.is_ipv4() => {
+        self.port_mode(control, user, port_addr).await
+      }
+      _ => {
+        let protocol = if local_addr.is_ipv4() { "1" } else { "2" };
+        control
+          .lock()
+          .await
+          .write_all(
+            format!("522 Network protocol not supported, use ({})\r\n", protocol).as_bytes(),
+          )
+          .await?;
+        Ok(())
+      }
+    }
+  }
+
+  async fn extended_passive_mode(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    arg: Option<String>,
+  ) -> Result<(), Box<dyn Error>> {
+    let protocol = match user.lock().await.local_addr {
+      SocketAddr::V4(_) => "1",
+      SocketAddr::V6(_) => "2",
+    };
+    match arg.as_deref() {
+      Some(all) if all.eq_ignore_ascii_case("ALL") => {
+        user.lock().await.epsv_all = true;
+        control
+          .lock()
+          .await
+          .write_all(b"200 EPSV ALL command successful.\r\n")
+          .await?;
+        return Ok(());
+      }
+      Some(requested) if requested != protocol => {
+        control
+          .lock()
+          .await
+          .write_all(
+            format!("522 Network protocol not supported, use ({})\r\n", protocol).as_bytes(),
+          )
+          .await?;
+        return Ok(());
+      }
+      _ => {}
+    }
+    let port = self.open_passive(user).await?.port();
+
+    control
+      .lock()
+      .await
+      .write_all(format!("229 Entering Extended Passive Mode (|||{}|)\r\n", port).as_bytes())
+      .await?;
     Ok(())
   }
 
   async fn port_mode(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     port_addr: SocketAddr,
   ) -> Result<(), Box<dyn Error>> {
     let mut user = user.lock().await;
+    // Connecting anywhere else would let the client bounce data off this
+    // server (RFC 2577).
+    if port_addr.ip().to_canonical() != user.addr.ip().to_canonical() {
+      control
+        .lock()
+        .await
+        .write_all(b"504 Data connections only go to the client's address.\r\n")
+        .await?;
+      return Ok(());
+    }
     let stream = TcpStream::connect(port_addr).await?;
 
-    user.set_new_session(TransferSession::new(TransferMode::Port(Arc::new(
-      Mutex::new(stream),
-    ))));
+    let (sender, receiver) = oneshot::channel::<BoxedStream>();
+    user.set_new_session(TransferSession::new(TransferMode::Port, receiver));
+    let tls = self.data_tls(&user);
+    // The client only starts its TLS handshake once the transfer command is
+    // answered, so the handshake cannot block the PORT reply.
+    tokio::spawn(async move {
+      if let Some(stream) = wrap_data_stream(stream, tls).await {
+        let _ = sender.send(stream);
+      }
+    });
 
     control
       .lock()
       .await
-      .write_all(b"200 PORT command successful.\r\n")
+      .write_all(b"200 Command okay.\r\n")
       .await?;
     Ok(())
   }
 
   async fn quit(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     let mut user = user.lock().await;
@@ -814,7 +1413,7 @@ impl FtpServer for Server {
 
   async fn noop(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     _user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     control.lock().await.write_all(b"200 NOOP ok.\r\n").await?;
@@ -823,13 +1422,14 @@ impl FtpServer for Server {
 
   async fn user(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     username: String,
   ) -> Result<(), Box<dyn Error>> {
     let mut user = user.lock().await;
     user.username = username;
     user.status = UserStatus::Logging;
+    user.account = None;
     control
       .lock()
       .await
@@ -840,12 +1440,73 @@ impl FtpServer for Server {
 
   async fn pass(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
-    _: String,
+    password: String,
   ) -> Result<(), Box<dyn Error>> {
+    let (username, addr) = {
+      let user = user.lock().await;
+      if user.status != UserStatus::Logging {
+        control
+          .lock()
+          .await
+          .write_all(b"503 Login with USER first.\r\n")
+          .await?;
+        return Ok(());
+      }
+      (user.username.clone(), user.addr)
+    };
+
+    let account = if self.anonymous && auth::is_anonymous(&username) {
+      let mut account = Account::new(&username);
+      account.home = self.anonymous_root.clone();
+      account.permissions = Permissions::read_only();
+      Some(account)
+    } else {
+      match self.authenticator.authenticate(&username, &password).await {
+        Ok(account) => account,
+        Err(e) => {
+          println!("Authentication error: {}", e);
+          None
+        }
+      }
+    };
+
+    let account = match account {
+      Some(account) => account,
+      None => {
+        println!("Login failed for user: {}, Addr: {}", username, addr);
+        user.lock().await.status = UserStatus::Inactive;
+        control
+          .lock()
+          .await
+          .write_all(b"530 Login incorrect.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+
+    let home = self.home_dir(&account);
+    let root = match self.storage.prepare_root(&home).await {
+      Ok(root) => root,
+      Err(e) => {
+        println!("Home directory unavailable: {}, {}", home.display(), e);
+        user.lock().await.status = UserStatus::Inactive;
+        control
+          .lock()
+          .await
+          .write_all(b"530 Cannot access home directory.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+
     {
-      user.lock().await.status = UserStatus::Active;
+      let mut user = user.lock().await;
+      user.set_root(root);
+      user.status = UserStatus::Active;
+      user.username = account.username.clone();
+      user.account = Some(account);
     }
     control
       .lock()
@@ -857,14 +1518,12 @@ impl FtpServer for Server {
 
   async fn abort(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
-    let mut locking = user.lock().await;
-    locking.status = UserStatus::Active;
-    let session = locking.get_session()?;
-    let mut session = session.lock().await;
-    session.aborted = true;
+    // A running transfer is aborted before this is called, all that is left
+    // is to close the data connection.
+    user.lock().await.session = None;
     control
       .lock()
       .await
@@ -875,7 +1534,7 @@ impl FtpServer for Server {
 
   async fn system_info(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     _user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     control
@@ -886,9 +1545,29 @@ impl FtpServer for Server {
     Ok(())
   }
 
+  async fn language(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    _user: Arc<Mutex<User>>,
+    language: Option<String>,
+  ) -> Result<(), Box<dyn Error>> {
+    // Replies only exist in English, in any of its variants like `en-US`.
+    let english = language.is_none_or(|tag| {
+      let tag = tag.to_uppercase();
+      tag == "EN" || tag.starts_with("EN-")
+    });
+    let reply: &[u8] = if english {
+      b"200 Language set to EN.\r\n"
+    } else {
+      b"504 Unsupported language.\r\n"
+    };
+    control.lock().await.write_all(reply).await?;
+    Ok(())
+  }
+
   async fn rename_from(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
@@ -906,17 +1585,28 @@ impl FtpServer for Server {
 
   async fn rename_to(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
     let user = user.lock().await;
-    let pwd = user.pwd();
     let session = user.get_session()?;
     let mut session = session.lock().await;
-    let old_path = Path::new(&self.root).join(&pwd).join(&session.file_name);
-    let new_path = Path::new(&self.root).join(&pwd).join(&file_name);
-    fs::rename(old_path, new_path)?;
+    let (old_path, new_path) = match (
+      user.resolve(&session.file_name).ok(),
+      user.resolve(&file_name).ok(),
+    ) {
+      (Some(old_path), Some(new_path)) => (old_path, new_path),
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    self.storage.rename(&old_path, &new_path).await?;
     session.file_name = file_name;
     {
       control
@@ -930,7 +1620,7 @@ impl FtpServer for Server {
 
   async fn restart(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     offset: u64,
   ) -> Result<(), Box<dyn Error>> {
@@ -948,7 +1638,7 @@ impl FtpServer for Server {
 
   async fn status(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     optional_path: Option<String>,
   ) -> Result<(), Box<dyn Error>> {
@@ -956,20 +1646,23 @@ impl FtpServer for Server {
     let mut control = control.lock().await;
     match optional_path {
       Some(path_str) => {
-        let path = Path::new(&self.root).join(&user.pwd()).join(&path_str);
-        if !path.exists() {
-          control.write_all(b"553 Not found.\r\n").await?;
-        } else {
-          let path = path.canonicalize()?;
-          if !path.starts_with(&self.root) {
-            control.write_all(b"550 Permission denied.\r\n").await?;
-          }
-          let list = get_list_lines(&path, false)?;
+        let path = user.resolve(&path_str).ok();
+        let metadata = match &path {
+          Some(path) => self.storage.metadata(path).await.ok(),
+          None => None,
+        };
+        if path.is_none() {
+          control.write_all(b"550 Permission denied.\r\n").await?;
+        } else if let (Some(path), Some(metadata)) = (path, metadata) {
+          let list =
+            get_list_lines(&*self.storage, &path, &metadata, false, user.charset()).await?;
           control
             .write_all(format!("213-Status of {}:\r\n", path_str).as_bytes())
             .await?;
           control.write_all(list.as_bytes()).await?;
           control.write_all(b"213 End of status.\r\n").await?;
+        } else {
+          control.write_all(b"553 Not found.\r\n").await?;
         }
       }
       None => {
@@ -977,8 +1670,29 @@ impl FtpServer for Server {
         let mut content = String::new();
         // content.push_str(format!("Server root: {}\r\n", self.root).as_str());
         content.push_str(format!("User: {}\r\n", user.username).as_str());
-        content.push_str(format!("Current directory: {}\r\n", user.pwd()).as_str());
+        content.push_str(format!("Current directory: {}\r\n", user.rendering_pwd()).as_str());
         content.push_str(format!("TYPE: {:?}\r\n", user.trans_type).as_str());
+        if let Some(account) = &user.account {
+          content.push_str(format!("Permissions: {}\r\n", account.permissions).as_str());
+          if let Some(quota) = account.quota {
+            content.push_str(format!("Quota: {} bytes\r\n", quota).as_str());
+          }
+        }
+        content.push_str(format!("TLS: {}, PROT: {:?}\r\n", user.is_secure(), user.prot).as_str());
+        if let Some(session) = user.session.clone() {
+          let session = session.lock().await;
+          content.push_str(format!("Data connection: {:?}\r\n", session.mode).as_str());
+          if !session.finished && !session.file_name.is_empty() {
+            content.push_str(
+              format!(
+                "Transferring {}: {}/{} bytes\r\n",
+                session.file_name, session.finished_size, session.total_size
+              )
+              .as_str(),
+            );
+          }
+        }
+        control.write_all(content.as_bytes()).await?;
         control.write_all(b"211 End of status.\r\n").await?;
       }
     }
@@ -987,7 +1701,7 @@ impl FtpServer for Server {
 
   async fn store_unique(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     let file_name = Uuid::new_v4().to_string();
@@ -996,7 +1710,7 @@ impl FtpServer for Server {
 
   async fn append(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
@@ -1012,7 +1726,7 @@ impl FtpServer for Server {
 
   async fn allocate(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     _user: Arc<Mutex<User>>,
     _size: u64,
   ) -> Result<(), Box<dyn Error>> {
@@ -1026,24 +1740,56 @@ impl FtpServer for Server {
 
   async fn feat(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
-    _user: Arc<Mutex<User>>,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
+    let (mlst, hash) = {
+      let user = user.lock().await;
+      (
+        mlsx::feat_line(&user.mlst_facts),
+        hash::feat_line(user.hash_algorithm),
+      )
+    };
     let mut locking = control.lock().await;
     locking.write_all(b"211-Features:\r\n").await?;
     locking.write_all(b" REST STREAM\r\n").await?;
     locking.write_all(b" MDTM\r\n").await?;
+    locking.write_all(b" SIZE\r\n").await?;
+    locking.write_all(b" MODE B\r\n").await?;
+    locking.write_all(b" MODE Z\r\n").await?;
+    locking.write_all(b" EPRT\r\n").await?;
+    locking.write_all(b" EPSV\r\n").await?;
+    locking
+      .write_all(format!(" MLST {}\r\n", mlst).as_bytes())
+      .await?;
+    locking
+      .write_all(format!(" HASH {}\r\n", hash).as_bytes())
+      .await?;
+    locking.write_all(b" UTF8\r\n").await?;
+    locking.write_all(b" LANG EN*\r\n").await?;
+    locking.write_all(b" MFMT\r\n").await?;
+    if mfxx::CREATE_SUPPORTED {
+      locking.write_all(b" MFCT\r\n").await?;
+    }
+    locking
+      .write_all(format!(" MFF {}\r\n", mfxx::feat_line()).as_bytes())
+      .await?;
+    if self.tls.is_some() {
+      locking.write_all(b" AUTH TLS\r\n").await?;
+      locking.write_all(b" PBSZ\r\n").await?;
+      locking.write_all(b" PROT\r\n").await?;
+    }
     locking.write_all(b"211 End.\r\n").await?;
     Ok(())
   }
 
   async fn cd_up(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
     let mut user = user.lock().await;
-    user.cwd("..")?;
+    user.cwd("..").await?;
     control
       .lock()
       .await
@@ -1054,42 +1800,482 @@ impl FtpServer for Server {
 
   async fn get_modify_timestamp(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     user: Arc<Mutex<User>>,
     file_name: String,
   ) -> Result<(), Box<dyn Error>> {
     let user = user.lock().await;
-    let path = Path::new(&self.root).join(&user.pwd()).join(&file_name);
-    if !path.exists() {
+    let path = match user.resolve(&file_name).ok() {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    let metadata = match self.storage.metadata(&path).await {
+      Ok(metadata) => metadata,
+      Err(_) => {
+        control
+          .lock()
+          .await
+          .write_all(b"553 Not found.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    // RFC 3659 time-val, in UTC like `MFMT` takes it.
+    let file_time = DateTime::<Utc>::from(
+      metadata
+        .modified
+        .ok_or("Error: modification time unavailable.")?,
+    )
+    .format("%Y%m%d%H%M%S")
+    .to_string();
+    control
+      .lock()
+      .await
+      .write_all(format!("213 {}\r\n", file_time).as_bytes())
+      .await?;
+    Ok(())
+  }
+
+  async fn protection_buffer_size(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    _size: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut user = user.lock().await;
+    if !user.is_secure() {
       control
         .lock()
         .await
-        .write_all(b"553 Not found.\r\n")
+        .write_all(b"503 PBSZ requires AUTH first.\r\n")
         .await?;
       return Ok(());
     }
-    if !path.starts_with(&self.root) {
+    // TLS does its own framing, so the only buffer size is 0.
+    user.pbsz = true;
+    control.lock().await.write_all(b"200 PBSZ=0\r\n").await?;
+    Ok(())
+  }
+
+  async fn protection_level(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    level: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut user = user.lock().await;
+    if !user.pbsz {
       control
         .lock()
         .await
-        .write_all(b"550 Permission denied.\r\n")
+        .write_all(b"503 PROT requires PBSZ first.\r\n")
         .await?;
       return Ok(());
     }
-    let metadata = fs::metadata(&path)?;
-    let file_time = metadata
-      .modified()?
-      .duration_since(std::time::SystemTime::UNIX_EPOCH)?;
-    let file_time = DateTime::from_timestamp(file_time.as_secs() as i64, 0)
-      .ok_or("Error: failed to convert timestamp.")?
-      .with_timezone(&Local)
-      .format("%Y%m%d%H:%M%S")
-      .to_string();
+    match level.to_uppercase().as_str() {
+      "C" => {
+        user.prot = DataProtection::Clear;
+        control
+          .lock()
+          .await
+          .write_all(b"200 Protection level set to Clear.\r\n")
+          .await?;
+      }
+      "P" => {
+        user.prot = DataProtection::Private;
+        control
+          .lock()
+          .await
+          .write_all(b"200 Protection level set to Private.\r\n")
+          .await?;
+      }
+      "S" | "E" => {
+        control
+          .lock()
+          .await
+          .write_all(b"536 Requested PROT level not supported by mechanism.\r\n")
+          .await?;
+      }
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"504 Command not implemented for that parameter.\r\n")
+          .await?;
+      }
+    }
+    Ok(())
+  }
+
+  async fn machine_list(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    optional_dir: Option<String>,
+  ) -> Result<(), Box<dyn Error>> {
+    self
+      .list_files(control, user, optional_dir, ListFormat::Machine)
+      .await
+  }
+
+  async fn machine_status(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    optional_path: Option<String>,
+  ) -> Result<(), Box<dyn Error>> {
+    let mut control = control.lock().await;
+    let user = user.lock().await;
+    let target = optional_path.as_deref().unwrap_or(".");
+    let path = match user.resolve(target).ok() {
+      Some(path) => path,
+      None => {
+        control.write_all(b"550 Permission denied.\r\n").await?;
+        return Ok(());
+      }
+    };
+    let metadata = match self.storage.metadata(&path).await {
+      Ok(metadata) => metadata,
+      Err(_) => {
+        control
+          .write_all(b"550 No such file or directory.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    let virtual_path = user.virtual_path(target);
+    let facts = self.facts_of(&user, &metadata, &virtual_path);
+    control
+      .write_all(
+        format!(
+          "250-Listing {}\r\n {} {}\r\n250 End.\r\n",
+          target, facts, virtual_path
+        )
+        .as_bytes(),
+      )
+      .await?;
+    Ok(())
+  }
+
+  async fn options(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    option: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let (name, value) = option.split_once(' ').unwrap_or((option.as_str(), ""));
+    match name.to_uppercase().as_str() {
+      "UTF8" => {
+        let utf8 = match value.trim().to_uppercase().as_str() {
+          "" | "ON" => Some(true),
+          "OFF" => Some(false),
+          _ => None,
+        };
+        let reply = match utf8 {
+          Some(utf8) => {
+            let mut user = user.lock().await;
+            user.utf8 = utf8;
+            let encoding = user.charset().encoding(utf8);
+            control.lock().await.set_encoding(encoding);
+            format!("200 UTF8 set to {}.\r\n", if utf8 { "on" } else { "off" })
+          }
+          None => "501 Option not understood.\r\n".to_string(),
+        };
+        control.lock().await.write_all(reply.as_bytes()).await?;
+      }
+      "MLST" => {
+        let facts = mlsx::parse_fact_list(value.trim());
+        let list = facts
+          .iter()
+          .map(|fact| format!("{};", fact.name()))
+          .collect::<String>();
+        user.lock().await.mlst_facts = facts;
+        control
+          .lock()
+          .await
+          .write_all(format!("200 MLST OPTS {}\r\n", list).as_bytes())
+          .await?;
+      }
+      "HASH" => {
+        let reply = if value.trim().is_empty() {
+          format!("200 {}\r\n", user.lock().await.hash_algorithm.name())
+        } else {
+          match HashAlgorithm::parse(value.trim()) {
+            Some(algorithm) => {
+              user.lock().await.hash_algorithm = algorithm;
+              format!("200 {}\r\n", algorithm.name())
+            }
+            None => "504 Unknown algorithm.\r\n".to_string(),
+          }
+        };
+        control.lock().await.write_all(reply.as_bytes()).await?;
+      }
+      "MODE" => {
+        let level = value
+          .to_uppercase()
+          .strip_prefix("Z LEVEL ")
+          .and_then(|level| level.trim().parse::<u32>().ok())
+          .filter(|level| *level <= 9);
+        let reply = match level {
+          Some(level) => {
+            user.lock().await.deflate_level = level;
+            format!("200 MODE Z LEVEL set to {}.\r\n", level)
+          }
+          None => "501 Option not understood.\r\n".to_string(),
+        };
+        control.lock().await.write_all(reply.as_bytes()).await?;
+      }
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"501 Option not understood.\r\n")
+          .await?;
+      }
+    }
+    Ok(())
+  }
+
+  async fn size(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    file_name: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let (path, ascii) = {
+      let user = user.lock().await;
+      (
+        user.resolve(&file_name).ok(),
+        matches!(user.trans_type, TransferType::ASCII),
+      )
+    };
+    let path = match path {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    let size = match self.storage.metadata(&path).await {
+      Ok(metadata) if !metadata.is_dir => metadata.len,
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Could not get file size.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    // In ASCII mode the size is the number of bytes actually sent, which
+    // takes reading the whole file.
+    let size = if !ascii {
+      size
+    } else if size > ASCII_SIZE_LIMIT {
+      control
+        .lock()
+        .await
+        .write_all(b"550 SIZE not allowed in ASCII mode for large files, use TYPE I.\r\n")
+        .await?;
+      return Ok(());
+    } else {
+      ascii::network_size(self.storage.open_read(&path, 0).await?).await?
+    };
     control
       .lock()
       .await
-      .write_all(format!("213 {}\r\n", file_time).as_bytes())
+      .write_all(format!("213 {}\r\n", size).as_bytes())
       .await?;
     Ok(())
   }
+
+  async fn modify_facts(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    facts: String,
+    file_name: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let changes = match mfxx::parse_changes(&facts) {
+      Ok(changes) if !changes.is_empty() => changes,
+      Ok(_) => {
+        control
+          .lock()
+          .await
+          .write_all(b"501 Syntax error in parameters or arguments.\r\n")
+          .await?;
+        return Ok(());
+      }
+      Err(ChangeError::Unsupported(fact)) => {
+        control
+          .lock()
+          .await
+          .write_all(format!("504 Fact {} cannot be set.\r\n", fact).as_bytes())
+          .await?;
+        return Ok(());
+      }
+      Err(ChangeError::Invalid(fact)) => {
+        control
+          .lock()
+          .await
+          .write_all(format!("501 Invalid fact {}.\r\n", fact).as_bytes())
+          .await?;
+        return Ok(());
+      }
+    };
+    let path = match user.lock().await.resolve(&file_name).ok() {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    if !self.storage.exists(&path).await {
+      control
+        .lock()
+        .await
+        .write_all(b"550 File not found.\r\n")
+        .await?;
+      return Ok(());
+    }
+    let reply = match self.storage.set_facts(&path, &changes).await {
+      Ok(()) => format!("213 {} {}\r\n", mfxx::facts(&changes), file_name),
+      Err(e) => {
+        println!("Failed to set facts of {}: {}", path.display(), e);
+        "550 Could not set file facts.\r\n".to_string()
+      }
+    };
+    control.lock().await.write_all(reply.as_bytes()).await?;
+    Ok(())
+  }
+
+  async fn hash(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    file_name: String,
+    algorithm: Option<HashAlgorithm>,
+  ) -> Result<(), Box<dyn Error>> {
+    let (path, selected, range) = {
+      let mut user = user.lock().await;
+      let range = match algorithm {
+        Some(_) => None,
+        None => user.hash_range.take(),
+      };
+      (user.resolve(&file_name).ok(), user.hash_algorithm, range)
+    };
+    let path = match path {
+      Some(path) => path,
+      None => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    let size = match self.storage.metadata(&path).await {
+      Ok(metadata) if !metadata.is_dir => metadata.len,
+      _ => {
+        control
+          .lock()
+          .await
+          .write_all(b"550 File not found.\r\n")
+          .await?;
+        return Ok(());
+      }
+    };
+    // Both ends of the range are inclusive, like in `RANG`.
+    let (start, end) = match range {
+      Some((start, end)) if start < size => (start, end.min(size - 1)),
+      Some(_) => {
+        control
+          .lock()
+          .await
+          .write_all(b"556 Invalid byte range.\r\n")
+          .await?;
+        return Ok(());
+      }
+      None => (0, size.saturating_sub(1)),
+    };
+    let len = if size == 0 { 0 } else { end - start + 1 };
+
+    let file = self.storage.open_read(&path, start).await?;
+    let digest = hash::hash_reader(file.take(len), algorithm.unwrap_or(selected)).await?;
+    let reply = match algorithm {
+      Some(_) => format!("250 {}\r\n", digest),
+      None => format!(
+        "213 {} {}-{} {} {}\r\n",
+        selected.name(),
+        start,
+        end,
+        digest,
+        file_name
+      ),
+    };
+    control.lock().await.write_all(reply.as_bytes()).await?;
+    Ok(())
+  }
+
+  async fn range(
+    &self,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+    range: String,
+  ) -> Result<(), Box<dyn Error>> {
+    let bounds = range
+      .split_whitespace()
+      .map(|bound| bound.parse::<u64>())
+      .collect::<Result<Vec<_>, _>>();
+    let reply = match bounds.as_deref() {
+      // `RANG 1 0` resets the range.
+      Ok([1, 0]) => {
+        user.lock().await.hash_range = None;
+        "350 Restarting at 0. Ending byte is EOF.\r\n".to_string()
+      }
+      Ok([start, end]) if start <= end => {
+        user.lock().await.hash_range = Some((*start, *end));
+        format!("350 Restarting at {}. Ending byte is {}.\r\n", start, end)
+      }
+      _ => "501 Syntax error in parameters or arguments.\r\n".to_string(),
+    };
+    control.lock().await.write_all(reply.as_bytes()).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lib::storage::LocalFsBackend;
+
+  #[tokio::test]
+  async fn test_dir_size_symlinks() {
+    let root = std::env::temp_dir().join("rftp-test-dir-size");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("a")).unwrap();
+    std::fs::write(root.join("a/b.txt"), b"hello\n").unwrap();
+    std::os::unix::fs::symlink("..", root.join("a/loop")).unwrap();
+
+    let size = dir_size(&LocalFsBackend::default(), &root).await;
+    assert_eq!(size, 6 + "..".len() as u64);
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
 }
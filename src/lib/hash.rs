@@ -0,0 +1,122 @@
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Algorithms of the `HASH` command (draft-bryan-ftp-hash) and its `X*`
+/// predecessors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+  Sha1,
+  Sha256,
+  Sha512,
+  Md5,
+  Crc32,
+}
+
+impl HashAlgorithm {
+  pub const ALL: [HashAlgorithm; 5] = [
+    HashAlgorithm::Sha1,
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Sha512,
+    HashAlgorithm::Md5,
+    HashAlgorithm::Crc32,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      HashAlgorithm::Sha1 => "SHA-1",
+      HashAlgorithm::Sha256 => "SHA-256",
+      HashAlgorithm::Sha512 => "SHA-512",
+      HashAlgorithm::Md5 => "MD5",
+      HashAlgorithm::Crc32 => "CRC32",
+    }
+  }
+
+  pub fn parse(name: &str) -> Option<HashAlgorithm> {
+    HashAlgorithm::ALL
+      .into_iter()
+      .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+  }
+}
+
+/// All supported algorithms for `FEAT`, the selected one marked with `*`.
+pub fn feat_line(selected: HashAlgorithm) -> String {
+  HashAlgorithm::ALL
+    .iter()
+    .map(|algorithm| {
+      let mark = if *algorithm == selected { "*" } else { "" };
+      format!("{}{}", algorithm.name(), mark)
+    })
+    .collect::<Vec<_>>()
+    .join(";")
+}
+
+/// Hex digest of everything `reader` yields, read in chunks so large files
+/// are never held in memory.
+pub async fn hash_reader(
+  reader: impl AsyncRead + Unpin,
+  algorithm: HashAlgorithm,
+) -> io::Result<String> {
+  match algorithm {
+    HashAlgorithm::Sha1 => digest::<Sha1>(reader).await,
+    HashAlgorithm::Sha256 => digest::<Sha256>(reader).await,
+    HashAlgorithm::Sha512 => digest::<Sha512>(reader).await,
+    HashAlgorithm::Md5 => digest::<Md5>(reader).await,
+    HashAlgorithm::Crc32 => {
+      let mut hasher = crc32fast::Hasher::new();
+      update(reader, |chunk| hasher.update(chunk)).await?;
+      Ok(format!("{:08x}", hasher.finalize()))
+    }
+  }
+}
+
+async fn digest<D: Digest>(reader: impl AsyncRead + Unpin) -> io::Result<String> {
+  let mut hasher = D::new();
+  update(reader, |chunk| hasher.update(chunk)).await?;
+  Ok(
+    hasher
+      .finalize()
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect(),
+  )
+}
+
+async fn update(mut reader: impl AsyncRead + Unpin, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+  let mut buf = vec![0; 64 * 1024];
+  loop {
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(());
+    }
+    f(&buf[..n]);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_hash_reader() {
+    let file = &b"hello\n"[..];
+    let hash = |algorithm, end| async move { hash_reader(&file[..end], algorithm).await.unwrap() };
+    assert_eq!(hash(HashAlgorithm::Crc32, 6).await, "363a3020");
+    assert_eq!(
+      hash(HashAlgorithm::Md5, 6).await,
+      "b1946ac92492d2347c6235b4d2611184"
+    );
+    assert_eq!(
+      hash(HashAlgorithm::Sha1, 5).await,
+      "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"
+    );
+
+    assert_eq!(HashAlgorithm::parse("sha-256"), Some(HashAlgorithm::Sha256));
+    assert_eq!(
+      feat_line(HashAlgorithm::Md5),
+      "SHA-1;SHA-256;SHA-512;MD5*;CRC32"
+    );
+  }
+}
@@ -0,0 +1,185 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::fs::{self, File, FileTimes, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Whether the creation time of a file can be set on this system.
+pub const CREATE_SUPPORTED: bool = cfg!(target_os = "macos");
+
+/// A fact changed by `MFMT`, `MFCT` or `MFF` (draft-somers-ftp-mfxx).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+  Modify(String),
+  Create(String),
+  UnixMode(u32),
+}
+
+/// Why a fact of `MFF` was rejected, with the offending fact.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeError {
+  /// The fact is unknown or cannot be changed on this system.
+  Unsupported(String),
+  /// The value of the fact is malformed.
+  Invalid(String),
+}
+
+impl Change {
+  fn parse(fact: &str) -> Result<Change, ChangeError> {
+    let invalid = || ChangeError::Invalid(fact.to_string());
+    let (name, value) = fact.split_once('=').ok_or_else(invalid)?;
+    match name.to_ascii_lowercase().as_str() {
+      "modify" => {
+        parse_time(value).ok_or_else(invalid)?;
+        Ok(Change::Modify(value.to_string()))
+      }
+      "create" if CREATE_SUPPORTED => {
+        parse_time(value).ok_or_else(invalid)?;
+        Ok(Change::Create(value.to_string()))
+      }
+      "unix.mode" => match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Change::UnixMode(mode)),
+        _ => Err(invalid()),
+      },
+      _ => Err(ChangeError::Unsupported(fact.to_string())),
+    }
+  }
+
+  fn fact(&self) -> String {
+    match self {
+      Change::Modify(time) => format!("modify={};", time),
+      Change::Create(time) => format!("create={};", time),
+      Change::UnixMode(mode) => format!("unix.mode=0{:o};", mode),
+    }
+  }
+}
+
+/// Facts that can be changed, as listed in `FEAT`.
+pub fn feat_line() -> &'static str {
+  if CREATE_SUPPORTED {
+    "modify;create;unix.mode;"
+  } else {
+    "modify;unix.mode;"
+  }
+}
+
+/// Parses a `YYYYMMDDHHMMSS[.sss]` time in UTC.
+pub fn parse_time(value: &str) -> Option<SystemTime> {
+  let time = NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S%.f").ok()?;
+  Some(DateTime::<Utc>::from_naive_utc_and_offset(time, Utc).into())
+}
+
+/// Parses the fact list of `MFF`, e.g. `modify=20240101120000;unix.mode=644;`.
+pub fn parse_changes(list: &str) -> Result<Vec<Change>, ChangeError> {
+  list
+    .split(';')
+    .filter(|fact| !fact.is_empty())
+    .map(Change::parse)
+    .collect()
+}
+
+/// The changed facts as echoed in the reply, e.g. `modify=20240101120000;`.
+pub fn facts(changes: &[Change]) -> String {
+  changes.iter().map(Change::fact).collect()
+}
+
+/// Applies all changes to the file at `path`. The mode is changed last, as
+/// it may take away the access needed to set the times.
+pub fn apply(path: &Path, changes: &[Change]) -> io::Result<()> {
+  let mut times = FileTimes::new();
+  let mut set_times = false;
+  let mut mode = None;
+  for change in changes {
+    match change {
+      Change::Modify(time) => {
+        times = times.set_modified(parse_time(time).ok_or(io::ErrorKind::InvalidInput)?);
+        set_times = true;
+      }
+      Change::Create(time) => {
+        times = set_created(times, parse_time(time).ok_or(io::ErrorKind::InvalidInput)?)?;
+        set_times = true;
+      }
+      Change::UnixMode(new_mode) => mode = Some(*new_mode),
+    }
+  }
+  if set_times {
+    File::open(path)?.set_times(times)?;
+  }
+  if let Some(mode) = mode {
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+  }
+  Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn set_created(times: FileTimes, time: SystemTime) -> io::Result<FileTimes> {
+  use std::os::macos::fs::FileTimesExt;
+  Ok(times.set_created(time))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn set_created(_times: FileTimes, _time: SystemTime) -> io::Result<FileTimes> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn test_parse_changes() {
+    let epoch = SystemTime::UNIX_EPOCH;
+    assert_eq!(
+      parse_time("19700101000001"),
+      Some(epoch + Duration::from_secs(1))
+    );
+    assert_eq!(
+      parse_time("19700101000001.250"),
+      Some(epoch + Duration::from_millis(1250))
+    );
+    assert_eq!(parse_time("1970010100"), None);
+
+    let changes = parse_changes("Modify=20240101120000;unix.mode=644;").unwrap();
+    assert_eq!(facts(&changes), "modify=20240101120000;unix.mode=0644;");
+    assert_eq!(
+      parse_changes("modify=20240101120000;size=1;"),
+      Err(ChangeError::Unsupported("size=1".to_string()))
+    );
+    assert_eq!(
+      parse_changes("unix.mode=99999;"),
+      Err(ChangeError::Invalid("unix.mode=99999".to_string()))
+    );
+  }
+
+  #[test]
+  fn test_apply() {
+    let path = std::env::temp_dir().join("rftp-test-mfxx-apply");
+    fs::write(&path, b"hello\n").unwrap();
+    fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+    // A write-only mode must not keep the times from being set.
+    let changes = parse_changes("unix.mode=0200;modify=20240101120000;").unwrap();
+    apply(&path, &changes).unwrap();
+    assert_eq!(mode(&path), 0o200);
+    assert_eq!(
+      fs::metadata(&path).unwrap().modified().unwrap(),
+      parse_time("20240101120000").unwrap()
+    );
+
+    // Nothing is changed when one of the changes fails.
+    fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+    if !CREATE_SUPPORTED {
+      let changes = [
+        Change::UnixMode(0o600),
+        Change::Create("20240101120000".to_string()),
+      ];
+      assert!(apply(&path, &changes).is_err());
+      assert_eq!(mode(&path), 0o644);
+    }
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+
+use crate::lib::permission::Permission;
+use crate::lib::storage::Metadata;
+
+/// Facts of a machine-readable listing entry (RFC 3659, section 7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fact {
+  Type,
+  Size,
+  Modify,
+  Perm,
+  Unique,
+  UnixMode,
+}
+
+impl Fact {
+  pub const ALL: [Fact; 6] = [
+    Fact::Type,
+    Fact::Size,
+    Fact::Modify,
+    Fact::Perm,
+    Fact::Unique,
+    Fact::UnixMode,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Fact::Type => "type",
+      Fact::Size => "size",
+      Fact::Modify => "modify",
+      Fact::Perm => "perm",
+      Fact::Unique => "unique",
+      Fact::UnixMode => "unix.mode",
+    }
+  }
+
+  fn parse(name: &str) -> Option<Fact> {
+    Fact::ALL
+      .into_iter()
+      .find(|fact| fact.name().eq_ignore_ascii_case(name))
+  }
+}
+
+/// Parses the fact list of `OPTS MLST`, e.g. `type;size;modify;`. Unknown
+/// facts are ignored and an empty list turns all facts off.
+pub fn parse_fact_list(list: &str) -> Vec<Fact> {
+  let mut facts = Vec::new();
+  for fact in list.split(';').filter_map(Fact::parse) {
+    if !facts.contains(&fact) {
+      facts.push(fact);
+    }
+  }
+  facts
+}
+
+/// All supported facts, the selected ones marked with `*` as in `FEAT`.
+pub fn feat_line(selected: &[Fact]) -> String {
+  Fact::ALL
+    .iter()
+    .map(|fact| {
+      let mark = if selected.contains(fact) { "*" } else { "" };
+      format!("{}{};", fact.name(), mark)
+    })
+    .collect()
+}
+
+/// The selected facts of a file, e.g. `type=file;size=6;`. `allows` tells
+/// which operations the user may perform on it, for the `perm` fact.
+pub fn facts(
+  metadata: &Metadata,
+  selected: &[Fact],
+  allows: impl Fn(Permission) -> bool,
+) -> String {
+  let mut facts = String::new();
+  for fact in selected {
+    let value = match fact {
+      Fact::Type if metadata.is_dir => "dir".to_string(),
+      Fact::Type => "file".to_string(),
+      Fact::Size if metadata.is_dir => continue,
+      Fact::Size => metadata.len.to_string(),
+      Fact::Modify => match metadata.modified {
+        Some(time) => DateTime::<Utc>::from(time)
+          .format("%Y%m%d%H%M%S")
+          .to_string(),
+        None => continue,
+      },
+      Fact::Perm => perm(metadata.is_dir, &allows),
+      Fact::Unique => match &metadata.unique {
+        Some(unique) => unique.clone(),
+        None => continue,
+      },
+      Fact::UnixMode => match metadata.mode {
+        Some(mode) => format!("0{:o}", mode),
+        None => continue,
+      },
+    };
+    facts.push_str(&format!("{}={};", fact.name(), value));
+  }
+  facts
+}
+
+fn perm(is_dir: bool, allows: impl Fn(Permission) -> bool) -> String {
+  let flags: &[(char, Permission)] = if is_dir {
+    &[
+      ('e', Permission::List),
+      ('l', Permission::List),
+      ('c', Permission::Write),
+      ('m', Permission::Mkdir),
+      ('p', Permission::Delete),
+      ('d', Permission::Delete),
+      ('f', Permission::Rename),
+    ]
+  } else {
+    &[
+      ('r', Permission::Read),
+      ('w', Permission::Write),
+      ('a', Permission::Write),
+      ('d', Permission::Delete),
+      ('f', Permission::Rename),
+    ]
+  };
+  flags
+    .iter()
+    .filter(|(_, permission)| allows(*permission))
+    .map(|(flag, _)| flag)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fact_list() {
+    assert_eq!(
+      parse_fact_list("Size;TYPE;bogus;size;"),
+      vec![Fact::Size, Fact::Type]
+    );
+    assert!(parse_fact_list("").is_empty());
+    assert_eq!(
+      feat_line(&[Fact::Type, Fact::UnixMode]),
+      "type*;size;modify;perm;unique;unix.mode*;"
+    );
+    assert_eq!(perm(false, |p| p != Permission::Write), "rdf");
+    assert_eq!(perm(true, |p| p == Permission::List), "el");
+  }
+}
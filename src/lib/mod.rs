@@ -1,5 +1,19 @@
+pub mod acl;
+pub mod ascii;
+pub mod auth;
+pub mod block;
+pub mod charset;
 pub mod commands;
+pub mod config;
 pub mod ftp;
+pub mod hash;
+pub mod mfxx;
+pub mod mlsx;
+pub mod permission;
 pub mod server;
 pub mod session;
+pub mod storage;
+pub mod stream;
+pub mod telnet;
+pub mod tls;
 pub mod user;
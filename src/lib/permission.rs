@@ -0,0 +1,79 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+  Read,
+  Write,
+  Delete,
+  Mkdir,
+  Rename,
+  List,
+}
+
+impl Permission {
+  pub const ALL: [Permission; 6] = [
+    Permission::Read,
+    Permission::Write,
+    Permission::Delete,
+    Permission::Mkdir,
+    Permission::Rename,
+    Permission::List,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Permission::Read => "read",
+      Permission::Write => "write",
+      Permission::Delete => "delete",
+      Permission::Mkdir => "mkdir",
+      Permission::Rename => "rename",
+      Permission::List => "list",
+    }
+  }
+}
+
+/// Operations an account is allowed to perform, e.g. `["read", "list"]` in a
+/// users file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "Vec<Permission>")]
+pub struct Permissions(HashSet<Permission>);
+
+impl Permissions {
+  pub fn all() -> Self {
+    Self(Permission::ALL.into_iter().collect())
+  }
+
+  pub fn read_only() -> Self {
+    Self::from(vec![Permission::Read, Permission::List])
+  }
+
+  pub fn allows(&self, permission: Permission) -> bool {
+    self.0.contains(&permission)
+  }
+}
+
+impl fmt::Display for Permissions {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let names = Permission::ALL
+      .iter()
+      .filter(|p| self.0.contains(p))
+      .map(|p| p.name())
+      .collect::<Vec<&str>>();
+    write!(f, "{}", names.join(","))
+  }
+}
+
+impl Default for Permissions {
+  fn default() -> Self {
+    Self::all()
+  }
+}
+
+impl From<Vec<Permission>> for Permissions {
+  fn from(list: Vec<Permission>) -> Self {
+    Self(list.into_iter().collect())
+  }
+}
@@ -1,18 +1,34 @@
 use crate::arg_parser::Args;
-use std::collections::HashMap;
+use rustls::ServerConfig;
+use socket2::SockRef;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::io;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::OwnedWriteHalf;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncWriteExt, ReadHalf};
+use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio_rustls::TlsAcceptor;
 
-use crate::lib::commands::{parse_command, FtpCommand};
+use crate::lib::acl::Acl;
+use crate::lib::auth::{self, Account, Authenticator};
+use crate::lib::charset::Charset;
+use crate::lib::commands::{parse_command, FtpCommand, ParseError};
+use crate::lib::config::Config;
 use crate::lib::ftp::FtpServer;
-use crate::lib::user::User;
+use crate::lib::hash::HashAlgorithm;
+use crate::lib::storage::{self, ArchiveBackend, StorageBackend};
+use crate::lib::stream::{BoxedStream, ControlLine, ControlReader, ControlStream, ControlWriter};
+use crate::lib::tls::{self, DataTls, TlsPolicy};
+use crate::lib::user::{DataProtection, User, UserStatus};
+
+/// How a command ended, as it may be cut short by the client.
+enum Dispatched {
+  Finished(Result<(), String>),
+  Aborted,
+}
 
 #[derive(Debug, Clone)]
 pub struct Server {
@@ -20,18 +36,71 @@ pub struct Server {
   pub port: u16,
   pub root: String,
   pub listener: Arc<TcpListener>,
+  pub implicit_listener: Option<Arc<TcpListener>>,
+  pub authenticator: Arc<dyn Authenticator>,
+  pub anonymous: bool,
+  pub anonymous_root: Option<String>,
+  pub homes: Option<String>,
+  pub acl: Arc<Acl>,
+  pub tls: Option<Arc<ServerConfig>>,
+  pub tls_policy: TlsPolicy,
+  pub charset: Charset,
+  pub storage: Arc<dyn StorageBackend>,
+  /// Generate archives of directories for `RETR`, see `Config::archive_dirs`.
+  pub archive_dirs: bool,
   pub user_map: Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<User>>>>>,
 }
 
 impl Server {
   pub async fn new(cfg: Args) -> Result<Self, tokio::io::Error> {
-    let listener = TcpListener::bind(format!("{}:{}", cfg.host, cfg.port)).await?;
+    let listener = TcpListener::bind((cfg.host.as_str(), cfg.port)).await?;
+    let implicit_listener = match cfg.implicit_port {
+      Some(port) => Some(Arc::new(
+        TcpListener::bind((cfg.host.as_str(), port)).await?,
+      )),
+      None => None,
+    };
+    let authenticator = auth::from_args(&cfg)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let config = match &cfg.config {
+      Some(path) => {
+        Config::load(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+      }
+      None => Config::default(),
+    };
+    let acl = Acl::new(&config.acl, config.groups)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let tls = match (&cfg.tls_cert, &cfg.tls_key) {
+      (Some(cert), Some(key)) => Some(
+        tls::load_config(cert, key)
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+      ),
+      _ => None,
+    };
+
+    let charset = match &cfg.charset {
+      Some(label) => {
+        Charset::new(label).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+      }
+      None => Charset::default(),
+    };
+    let storage = storage::from_args(&cfg, charset)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let root = storage.prepare_root(Path::new(&cfg.folder)).await?;
+    let storage: Arc<dyn StorageBackend> = if cfg.browse_archives || !config.archives.is_empty() {
+      let patterns = (!cfg.browse_archives).then_some(config.archives.as_slice());
+      Arc::new(
+        ArchiveBackend::new(storage, root.clone(), patterns)
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+      )
+    } else {
+      storage
+    };
 
     Ok(Self {
       host: cfg.host,
       port: cfg.port,
-      root: Path::new(cfg.folder.as_str())
-        .canonicalize()?
+      root: root
         .to_str()
         .ok_or(io::Error::new(
           io::ErrorKind::NotFound,
@@ -39,6 +108,21 @@ impl Server {
         ))?
         .to_string(),
       listener: Arc::new(listener),
+      implicit_listener,
+      authenticator,
+      anonymous: cfg.anonymous,
+      anonymous_root: cfg.anonymous_root,
+      homes: cfg.homes,
+      acl: Arc::new(acl),
+      tls,
+      tls_policy: TlsPolicy {
+        require_auth: cfg.require_tls,
+        require_prot: cfg.require_prot_p,
+        require_reuse: cfg.require_tls_reuse,
+      },
+      charset,
+      storage,
+      archive_dirs: config.archive_dirs,
       user_map: Arc::new(Mutex::new(HashMap::new())),
     })
   }
@@ -46,11 +130,62 @@ impl Server {
   pub async fn listen(&self) {
     println!("Listening on {}:{}", self.host, self.port);
     println!("Root folder: {}", self.root);
+    if let Some(listener) = self.implicit_listener.clone() {
+      if let Ok(addr) = listener.local_addr() {
+        println!("Listening for implicit FTPS on {}", addr);
+      }
+      let shared_self = self.clone();
+      tokio::spawn(async move {
+        shared_self.listen_implicit(listener).await;
+      });
+    }
     loop {
       if let Ok((socket, addr)) = self.listener.accept().await {
+        let local_addr = match socket.local_addr() {
+          Ok(local_addr) => local_addr,
+          Err(_) => continue,
+        };
+        // Keeps the urgent data some clients send with `ABOR` in line, where
+        // the Telnet layer strips it.
+        if let Err(e) = SockRef::from(&socket).set_out_of_band_inline(true) {
+          println!("Failed to set SO_OOBINLINE: {}", e);
+        }
+        let shared_self = self.clone();
+        tokio::spawn(async move {
+          shared_self
+            .handle(Box::new(ControlStream::new(socket)), addr, local_addr, None)
+            .await;
+        });
+      } else {
+        continue;
+      }
+    }
+  }
+
+  /// Accept loop of the implicit FTPS listener, where the TLS handshake
+  /// happens right away instead of after `AUTH TLS`.
+  async fn listen_implicit(&self, listener: Arc<TcpListener>) {
+    let config = match &self.tls {
+      Some(config) => config.clone(),
+      None => return,
+    };
+    loop {
+      if let Ok((socket, addr)) = listener.accept().await {
+        let local_addr = match socket.local_addr() {
+          Ok(local_addr) => local_addr,
+          Err(_) => continue,
+        };
         let shared_self = self.clone();
+        let (control, data) = tls::session_configs(&config);
         tokio::spawn(async move {
-          shared_self.handle(socket, addr).await;
+          match TlsAcceptor::from(control).accept(socket).await {
+            Ok(stream) => {
+              shared_self
+                .handle(Box::new(stream), addr, local_addr, Some(data))
+                .await
+            }
+            Err(e) => println!("TLS handshake failed: {}, Addr: {}", e, addr),
+          }
         });
       } else {
         continue;
@@ -58,14 +193,23 @@ impl Server {
     }
   }
 
-  pub async fn handle(&self, socket: TcpStream, addr: SocketAddr) {
+  /// Serves one control connection. `tls` is the data connection config of
+  /// an implicit FTPS connection, which is already wrapped in TLS.
+  pub async fn handle(
+    &self,
+    socket: BoxedStream,
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    tls: Option<Arc<ServerConfig>>,
+  ) {
     let user_map = self.user_map.clone();
-    let (mut reader, mut writer) = socket.into_split();
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = ControlReader::new(reader);
 
     println!("New connection: {}", addr);
     {
       let mut user_map_locked = user_map.lock().await;
-      if !user_map_locked.contains_key(&addr) {
+      if let std::collections::hash_map::Entry::Vacant(e) = user_map_locked.entry(addr) {
         if let Err(e) = writer
           .write_all(b"220 rftp.whiteffire.cn FTP server ready.\r\n")
           .await
@@ -74,35 +218,62 @@ impl Server {
           return;
         }
 
-        let new_user = match User::new_anonymous(addr, &self.root) {
-          Ok(u) => u,
-          Err(e) => {
-            println!("Failed to create new user: {}", e);
-            return;
-          }
-        };
+        let mut new_user = User::new(
+          String::new(),
+          addr,
+          local_addr,
+          PathBuf::from(&self.root),
+          self.storage.clone(),
+        );
+        if tls.is_some() {
+          // Implicit FTPS protects the data connections by default as well.
+          new_user.tls = tls.clone();
+          new_user.pbsz = true;
+          new_user.prot = DataProtection::Private;
+        }
+        new_user.set_charset(self.charset);
 
-        user_map_locked.insert(addr.clone(), Arc::new(Mutex::new(new_user)));
+        e.insert(Arc::new(Mutex::new(new_user)));
       }
     }
+    let mut writer = ControlWriter::new(writer);
+    writer.set_encoding(self.charset.encoding(false));
     let writer_guard = Arc::new(Mutex::new(writer));
+    // Commands that arrived while the previous one was running.
+    let mut queued = VecDeque::new();
+    // Set once the client stopped sending, possibly while a command was
+    // still running.
+    let mut closed = false;
     loop {
-      let mut buf = vec![0; 2048];
-      let req = {
-        let n = match reader.read(&mut buf).await {
-          Ok(n) => n,
-          Err(_) => {
-            println!("Connection closed: {}", addr);
-            user_map.lock().await.remove(&addr);
-            return;
+      let next = match queued.pop_front() {
+        Some(cmd) => Some(cmd),
+        None if closed => None,
+        None => self.read_command(&mut reader, &writer_guard).await,
+      };
+      let cmd = match next {
+        Some(cmd) => cmd,
+        None => {
+          println!("Connection closed: {}", addr);
+          user_map.lock().await.remove(&addr);
+          return;
+        }
+      };
+      let cmd = match cmd {
+        Ok(cmd) => cmd,
+        Err(e) => {
+          println!("Addr: {}, {}", addr, e);
+          let reply: &[u8] = match e {
+            ParseError::Unrecognized(_) => b"500 Syntax error, command unrecognized.\r\n",
+            ParseError::InvalidArgument(_) => b"501 Syntax error in parameters or arguments.\r\n",
+            ParseError::NotImplemented(_) => b"502 Command not implemented.\r\n",
+          };
+          if let Err(e) = writer_guard.lock().await.write_all(reply).await {
+            println!("Failed to respond error: {}", e)
           }
-        };
-        String::from_utf8_lossy(&buf[..n]).to_string()
+          continue;
+        }
       };
 
-      if req.is_empty() {
-        continue;
-      }
       let cloned_writer = writer_guard.clone();
       let user = match user_map.lock().await.get(&addr) {
         Some(u) => u.clone(),
@@ -111,11 +282,34 @@ impl Server {
           return;
         }
       };
-      let cloned_self = self.clone();
 
-      let cmd = parse_command(req);
       println!("Addr: {}, Cmd: {:?}", addr, cmd);
 
+      if let FtpCommand::AUTH(mechanism) = cmd {
+        if let Some(refusal) = self.auth_refusal(&user, &mechanism).await {
+          // Nothing changes, so pipelined commands are still good.
+          if let Err(e) = cloned_writer.lock().await.write_all(refusal).await {
+            println!("Failed to respond error: {}", e)
+          }
+          continue;
+        }
+        let upgraded = self
+          .auth(reader.into_inner(), cloned_writer, user)
+          .await
+          .map_err(|e| e.to_string());
+        reader = match upgraded {
+          Ok(reader) => ControlReader::new(reader),
+          Err(e) => {
+            println!("TLS handshake failed: {}, Addr: {}", e, addr);
+            user_map.lock().await.remove(&addr);
+            return;
+          }
+        };
+        // Anything pipelined behind `AUTH` was sent in the clear.
+        queued.clear();
+        continue;
+      }
+
       if cmd == FtpCommand::QUIT {
         {
           let _ = self.quit(cloned_writer, user).await;
@@ -124,37 +318,254 @@ impl Server {
         return;
       }
 
-      tokio::spawn(async move {
-        let cloned = cloned_writer.clone();
-        let error_msg = match cloned_self.dispatch(cloned_writer.clone(), cmd, user).await {
-          Err(e) => String::from(e.to_string()),
-          Ok(_) => String::new(),
-        };
-        if !error_msg.is_empty() {
-          println!("Error occurs: {}", error_msg);
-          let mut writer = cloned.lock().await;
+      // Commands are processed strictly in order. While one is running the
+      // control connection is still read, so that `STAT` can be answered and
+      // `ABOR` can cut a transfer short right away. Everything else, `ABOR`
+      // of other commands included, waits in `queued`. A client that stops
+      // sending still gets the running command finished.
+      let transfer = cmd.uses_data_connection();
+      let dispatched = {
+        let dispatch = self.dispatch(cloned_writer.clone(), cmd, user.clone());
+        tokio::pin!(dispatch);
+        loop {
+          tokio::select! {
+            result = &mut dispatch => break Dispatched::Finished(result.map_err(|e| e.to_string())),
+            line = reader.next_line(), if !closed => match line {
+              Ok(Some(line)) => match self.interject(line, &cloned_writer, &user) {
+                Some(Ok(FtpCommand::ABOR)) if transfer => break Dispatched::Aborted,
+                Some(cmd) => queued.push_back(cmd),
+                None => {}
+              },
+              Ok(None) | Err(_) => closed = true,
+            }
+          }
+        }
+      };
+
+      match dispatched {
+        Dispatched::Finished(Ok(())) => {}
+        Dispatched::Finished(Err(e)) => {
+          println!("Error occurs: {}", e);
+          let mut writer = cloned_writer.lock().await;
           if let Err(e) = writer
-            .write_all(format!("550 Error occurs: {}", error_msg).as_bytes())
+            .write_all(format!("550 Error occurs: {}\r\n", e).as_bytes())
             .await
           {
             println!("Failed to respond error: {}", e)
           }
         }
-      });
+        Dispatched::Aborted => {
+          // The running transfer was dropped where it was. It gets its
+          // reply before the one to `ABOR`.
+          println!("Addr: {}, Cmd: {:?}", addr, FtpCommand::ABOR);
+          {
+            let mut writer = cloned_writer.lock().await;
+            if let Err(e) = writer
+              .write_all(b"426 Connection closed; transfer aborted.\r\n")
+              .await
+            {
+              println!("Failed to respond error: {}", e)
+            }
+          }
+          if let Err(e) = self
+            .abort(cloned_writer, user)
+            .await
+            .map_err(|e| e.to_string())
+          {
+            println!("Error occurs: {}", e);
+          }
+        }
+      }
+    }
+  }
+
+  /// Handles a line that arrived while another command is running. `STAT`
+  /// and overlong lines are answered right away, anything else is returned
+  /// to be aborted or queued.
+  fn interject(
+    &self,
+    line: ControlLine,
+    control: &Arc<Mutex<ControlWriter>>,
+    user: &Arc<Mutex<User>>,
+  ) -> Option<Result<FtpCommand, ParseError>> {
+    // Replies are written from new tasks, since the running command may hold
+    // the locks they need until it gets polled again.
+    let control = control.clone();
+    match line {
+      ControlLine::Command(line) => {
+        let req = self.charset.decode(&line);
+        if req.trim().is_empty() {
+          return None;
+        }
+        let cmd = parse_command(&req);
+        if cmd != Ok(FtpCommand::STAT(None)) {
+          return Some(cmd);
+        }
+        let (server, user) = (self.clone(), user.clone());
+        tokio::spawn(async move {
+          let result = server.status(control, user, None).await;
+          if let Err(e) = result.map_err(|e| e.to_string()) {
+            println!("Error occurs: {}", e);
+          }
+        });
+      }
+      ControlLine::TooLong => {
+        tokio::spawn(async move {
+          let mut writer = control.lock().await;
+          if let Err(e) = writer.write_all(b"500 Command line too long.\r\n").await {
+            println!("Failed to respond error: {}", e)
+          }
+        });
+      }
+    }
+    None
+  }
+
+  /// Reads the next command, answering overlong lines on the way. `None`
+  /// once the client closed the connection.
+  async fn read_command(
+    &self,
+    reader: &mut ControlReader,
+    control: &Arc<Mutex<ControlWriter>>,
+  ) -> Option<Result<FtpCommand, ParseError>> {
+    loop {
+      match reader.next_line().await {
+        Ok(Some(ControlLine::Command(line))) => {
+          let req = self.charset.decode(&line);
+          if !req.trim().is_empty() {
+            return Some(parse_command(&req));
+          }
+        }
+        Ok(Some(ControlLine::TooLong)) => {
+          let mut writer = control.lock().await;
+          if let Err(e) = writer.write_all(b"500 Command line too long.\r\n").await {
+            println!("Failed to respond error: {}", e)
+          }
+        }
+        Ok(None) | Err(_) => return None,
+      }
     }
   }
 
+  /// Reply refusing `AUTH` with `mechanism`, if any.
+  async fn auth_refusal(&self, user: &Arc<Mutex<User>>, mechanism: &str) -> Option<&'static [u8]> {
+    if self.tls.is_none() {
+      Some(b"502 TLS is not configured.\r\n")
+    } else if !matches!(mechanism.to_uppercase().as_str(), "TLS" | "TLS-C" | "SSL") {
+      Some(b"504 Unsupported security mechanism.\r\n")
+    } else if user.lock().await.is_secure() {
+      Some(b"503 Control connection is already protected.\r\n")
+    } else {
+      None
+    }
+  }
+
+  /// Handles `AUTH TLS` by upgrading the control connection in place, once
+  /// `auth_refusal` let it through. It lives outside of `dispatch` since it
+  /// needs the read half as well.
+  async fn auth(
+    &self,
+    reader: ReadHalf<BoxedStream>,
+    control: Arc<Mutex<ControlWriter>>,
+    user: Arc<Mutex<User>>,
+  ) -> Result<ReadHalf<BoxedStream>, Box<dyn Error>> {
+    let config = self.tls.clone().ok_or("TLS is not configured")?;
+    let mut control = control.lock().await;
+    control.write_all(b"234 AUTH TLS successful.\r\n").await?;
+    let writer = control.take().ok_or("Control connection is gone")?;
+    let (config, data) = tls::session_configs(&config);
+    let stream = TlsAcceptor::from(config)
+      .accept(reader.unsplit(writer))
+      .await?;
+    let (reader, writer) = tokio::io::split(Box::new(stream) as BoxedStream);
+    control.replace(writer);
+
+    // A new security context starts a new login (RFC 4217, section 4).
+    let mut user = user.lock().await;
+    user.tls = Some(data);
+    user.status = UserStatus::Inactive;
+    user.account = None;
+    Ok(reader)
+  }
+
   async fn dispatch(
     &self,
-    control: Arc<Mutex<OwnedWriteHalf>>,
+    control: Arc<Mutex<ControlWriter>>,
     cmd: FtpCommand,
     user: Arc<Mutex<User>>,
   ) -> Result<(), Box<dyn Error>> {
+    if self.tls_policy.require_auth
+      && matches!(cmd, FtpCommand::USER(_) | FtpCommand::PASS(_))
+      && !user.lock().await.is_secure()
+    {
+      control
+        .lock()
+        .await
+        .write_all(b"530 TLS is required, use AUTH TLS first.\r\n")
+        .await?;
+      return Ok(());
+    }
+
+    if cmd.requires_login() && !user.lock().await.is_logged_in() {
+      control
+        .lock()
+        .await
+        .write_all(b"530 Please login with USER and PASS.\r\n")
+        .await?;
+      return Ok(());
+    }
+
+    if self.tls_policy.require_prot
+      && cmd.uses_data_connection()
+      && user.lock().await.prot == DataProtection::Clear
+    {
+      control
+        .lock()
+        .await
+        .write_all(b"521 Data connections must be protected, use PROT P.\r\n")
+        .await?;
+      return Ok(());
+    }
+
+    if matches!(
+      cmd,
+      FtpCommand::PORT(_) | FtpCommand::PASV | FtpCommand::EPRT(_)
+    ) && user.lock().await.epsv_all
+    {
+      control
+        .lock()
+        .await
+        .write_all(b"503 Only EPSV is allowed after EPSV ALL.\r\n")
+        .await?;
+      return Ok(());
+    }
+
+    if let Some(permission) = cmd.required_permission() {
+      let allowed = {
+        let user = user.lock().await;
+        let path = user.virtual_path(cmd.target_path());
+        user
+          .account
+          .as_ref()
+          .is_some_and(|account| self.acl.allows(account, &path, permission))
+      };
+      if !allowed {
+        control
+          .lock()
+          .await
+          .write_all(b"550 Permission denied.\r\n")
+          .await?;
+        return Ok(());
+      }
+    }
+
     match cmd {
       FtpCommand::USER(username) => self.user(control, user, username).await,
       FtpCommand::PASS(pwd) => self.pass(control, user, pwd).await,
       FtpCommand::PORT(addr) => self.port_mode(control, user, addr).await,
       FtpCommand::PASV => self.passive_mode(control, user).await,
+      FtpCommand::EPRT(addr) => self.extended_port_mode(control, user, addr).await,
+      FtpCommand::EPSV(arg) => self.extended_passive_mode(control, user, arg).await,
       FtpCommand::RETR(file_name) => self.retrieve(control, user, file_name).await,
       FtpCommand::STOR(file_name) => self.store(control, user, file_name).await,
       FtpCommand::ABOR => self.abort(control, user).await,
@@ -162,8 +573,10 @@ impl Server {
         // NOTES: QUIT command is handled in the main loop
         self.quit(control, user).await
       }
+      FtpCommand::LANG(language) => self.language(control, user, language).await,
       FtpCommand::SYST => self.system_info(control, user).await,
       FtpCommand::TYPE(type_) => self.set_type(control, user, type_).await,
+      FtpCommand::MODE(mode) => self.set_mode(control, user, mode).await,
       FtpCommand::RNFR(file_name) => self.rename_from(control, user, file_name).await,
       FtpCommand::RNTO(file_name) => self.rename_to(control, user, file_name).await,
       FtpCommand::PWD => self.pwd(control, user).await,
@@ -181,20 +594,821 @@ impl Server {
       FtpCommand::FEAT => self.feat(control, user).await,
       FtpCommand::CDUP => self.cd_up(control, user).await,
       FtpCommand::MDTM(filename) => self.get_modify_timestamp(control, user, filename).await,
+      FtpCommand::SIZE(filename) => self.size(control, user, filename).await,
+      FtpCommand::MFMT(time, filename) => {
+        let facts = format!("modify={}", time);
+        self.modify_facts(control, user, facts, filename).await
+      }
+      FtpCommand::MFCT(time, filename) => {
+        let facts = format!("create={}", time);
+        self.modify_facts(control, user, facts, filename).await
+      }
+      FtpCommand::MFF(facts, filename) => self.modify_facts(control, user, facts, filename).await,
+      FtpCommand::HASH(filename) => self.hash(control, user, filename, None).await,
+      FtpCommand::RANG(range) => self.range(control, user, range).await,
+      FtpCommand::XCRC(filename) => {
+        let algorithm = Some(HashAlgorithm::Crc32);
+        self.hash(control, user, filename, algorithm).await
+      }
+      FtpCommand::XMD5(filename) => {
+        let algorithm = Some(HashAlgorithm::Md5);
+        self.hash(control, user, filename, algorithm).await
+      }
+      FtpCommand::XSHA1(filename) => {
+        let algorithm = Some(HashAlgorithm::Sha1);
+        self.hash(control, user, filename, algorithm).await
+      }
+      FtpCommand::XSHA256(filename) => {
+        let algorithm = Some(HashAlgorithm::Sha256);
+        self.hash(control, user, filename, algorithm).await
+      }
+      FtpCommand::XSHA512(filename) => {
+        let algorithm = Some(HashAlgorithm::Sha512);
+        self.hash(control, user, filename, algorithm).await
+      }
       FtpCommand::NLST(optional_dir) => self.name_list(control, user, optional_dir).await,
+      FtpCommand::MLSD(optional_dir) => self.machine_list(control, user, optional_dir).await,
+      FtpCommand::MLST(optional_path) => self.machine_status(control, user, optional_path).await,
+      FtpCommand::OPTS(option) => self.options(control, user, option).await,
+      FtpCommand::AUTH(_) => {
+        // NOTES: AUTH command is handled in the main loop
+        Ok(())
+      }
+      FtpCommand::PBSZ(size) => self.protection_buffer_size(control, user, size).await,
+      FtpCommand::PROT(level) => self.protection_level(control, user, level).await,
     }
   }
 
-  pub async fn generate_pasv_addr(&self) -> Result<TcpListener, Box<dyn Error>> {
+  /// Directory the account is jailed to. Relative homes are resolved against
+  /// the server root.
+  pub fn home_dir(&self, account: &Account) -> PathBuf {
+    match (&account.home, &self.homes) {
+      (Some(home), _) => Path::new(&self.root).join(home),
+      (None, Some(homes)) => Path::new(homes).join(&account.username),
+      (None, None) => PathBuf::from(&self.root),
+    }
+  }
+
+  /// TLS settings for the next data connection of `user`, if it asked for
+  /// `PROT P`.
+  pub fn data_tls(&self, user: &User) -> Option<DataTls> {
+    match (&user.tls, &user.prot) {
+      (Some(config), DataProtection::Private) => {
+        Some(DataTls::new(config.clone(), self.tls_policy.require_reuse))
+      }
+      _ => None,
+    }
+  }
+
+  /// Binds a passive data listener on `ip`, the address the client reached
+  /// the control connection on.
+  pub async fn generate_pasv_addr(&self, ip: IpAddr) -> Result<TcpListener, Box<dyn Error>> {
     for port in 49152..65535 {
-      let addr = format!("{}:{}", self.host, port);
-      if let Ok(addr) = addr.parse::<SocketAddr>() {
-        match TcpListener::bind(addr).await {
-          Ok(listener) => return Ok(listener),
-          Err(_) => continue,
-        }
+      match TcpListener::bind(SocketAddr::new(ip, port)).await {
+        Ok(listener) => return Ok(listener),
+        Err(_) => continue,
       }
     }
     Err("Failed to generate PASV address".into())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lib::stream::AsyncStream;
+  use clap::Parser;
+  use flate2::read::GzDecoder;
+  use rustls::pki_types::pem::PemObject;
+  use rustls::pki_types::{CertificateDer, ServerName};
+  use rustls::{ClientConfig, RootCertStore};
+  use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, WriteHalf};
+  use tokio::net::TcpStream;
+  use tokio::time::Duration;
+  use tokio_rustls::TlsConnector;
+
+  async fn server(folder: &Path, args: &[&str]) -> Server {
+    let mut argv = vec!["rftp", "--port", "0", "--folder", folder.to_str().unwrap()];
+    argv.extend_from_slice(args);
+    Server::new(Args::parse_from(argv)).await.unwrap()
+  }
+
+  /// Starts a server on a free port for `folder`, with extra arguments.
+  async fn start(folder: &Path, args: &[&str]) -> SocketAddr {
+    let server = server(folder, args).await;
+    let addr = server.listener.local_addr().unwrap();
+    tokio::spawn(async move { server.listen().await });
+    addr
+  }
+
+  fn test_data(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+  }
+
+  /// Starts a server with the test certificate and an implicit FTPS
+  /// listener, returning the explicit and the implicit address.
+  async fn start_tls(folder: &Path, args: &[&str]) -> (SocketAddr, SocketAddr) {
+    let (cert, key) = (test_data("cert.pem"), test_data("key.pem"));
+    let mut argv = vec![
+      "--tls-cert",
+      &cert,
+      "--tls-key",
+      &key,
+      "--implicit-port",
+      "0",
+    ];
+    argv.extend_from_slice(args);
+    let server = server(folder, &argv).await;
+    let addr = server.listener.local_addr().unwrap();
+    let implicit = server
+      .implicit_listener
+      .as_ref()
+      .unwrap()
+      .local_addr()
+      .unwrap();
+    tokio::spawn(async move { server.listen().await });
+    (addr, implicit)
+  }
+
+  /// Client side TLS config trusting the test CA. Each config keeps its own
+  /// session cache.
+  fn client_tls() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let ca = CertificateDer::from_pem_file(test_data("ca.pem")).unwrap();
+    roots.add(ca).unwrap();
+    let config =
+      ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Arc::new(config)
+  }
+
+  async fn tls_connect<S: AsyncStream + 'static>(
+    config: &Arc<ClientConfig>,
+    stream: S,
+  ) -> std::io::Result<BoxedStream> {
+    let name = ServerName::try_from("localhost").unwrap();
+    let stream = TlsConnector::from(config.clone()).connect(name, stream);
+    let stream = tokio::time::timeout(Duration::from_secs(10), stream).await??;
+    Ok(Box::new(stream))
+  }
+
+  /// A bare control connection.
+  struct Client {
+    reader: BufReader<ReadHalf<BoxedStream>>,
+    writer: WriteHalf<BoxedStream>,
+    addr: SocketAddr,
+  }
+
+  impl Client {
+    async fn connect(addr: SocketAddr) -> Self {
+      let stream = TcpStream::connect(addr).await.unwrap();
+      Self::greeted(Box::new(stream), addr).await
+    }
+
+    /// Connects to an implicit FTPS listener.
+    async fn connect_tls(addr: SocketAddr, config: &Arc<ClientConfig>) -> Self {
+      let stream = TcpStream::connect(addr).await.unwrap();
+      let stream = tls_connect(config, stream).await.unwrap();
+      Self::greeted(stream, addr).await
+    }
+
+    async fn greeted(stream: BoxedStream, addr: SocketAddr) -> Self {
+      let (reader, writer) = tokio::io::split(stream);
+      let mut client = Self {
+        reader: BufReader::new(reader),
+        writer,
+        addr,
+      };
+      assert!(client.reply().await.starts_with("220 "));
+      client
+    }
+
+    /// Upgrades the control connection with `AUTH TLS`.
+    async fn auth_tls(self, config: &Arc<ClientConfig>) -> Self {
+      let mut client = self;
+      assert!(client.cmd("AUTH TLS").await.starts_with("234 "));
+      let stream = client.reader.into_inner().unsplit(client.writer);
+      let (reader, writer) = tokio::io::split(tls_connect(config, stream).await.unwrap());
+      Self {
+        reader: BufReader::new(reader),
+        writer,
+        addr: client.addr,
+      }
+    }
+
+    async fn send(&mut self, line: &str) {
+      self
+        .writer
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .unwrap();
+    }
+
+    /// The last line of the next reply.
+    async fn reply(&mut self) -> String {
+      loop {
+        let mut line = String::new();
+        let read = self.reader.read_line(&mut line);
+        tokio::time::timeout(Duration::from_secs(10), read)
+          .await
+          .expect("no reply")
+          .unwrap();
+        if line.len() < 4 || line.as_bytes()[3] == b' ' {
+          return line.trim_end().to_string();
+        }
+      }
+    }
+
+    async fn cmd(&mut self, line: &str) -> String {
+      self.send(line).await;
+      self.reply().await
+    }
+
+    async fn login(&mut self, username: &str, password: &str) {
+      assert!(self
+        .cmd(&format!("USER {}", username))
+        .await
+        .starts_with("331 "));
+      assert!(self
+        .cmd(&format!("PASS {}", password))
+        .await
+        .starts_with("230 "));
+    }
+
+    /// Data connection set up with `EPSV`.
+    async fn passive(&mut self) -> TcpStream {
+      let reply = self.cmd("EPSV").await;
+      let port = reply.split('|').nth(3).unwrap().parse::<u16>().unwrap();
+      TcpStream::connect((self.addr.ip(), port)).await.unwrap()
+    }
+  }
+
+  fn scratch(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    root
+  }
+
+  /// A users file in `root` with `alice`/`secret` and the given permissions.
+  fn users_file(root: &Path, permissions: &str) -> PathBuf {
+    let path = root.join("users.toml");
+    let hash = auth::hash_password("secret").unwrap();
+    std::fs::write(
+      &path,
+      format!(
+        "[[users]]\nusername = \"alice\"\npassword = \"{}\"\npermissions = {}\n",
+        hash, permissions
+      ),
+    )
+    .unwrap();
+    path
+  }
+
+  #[tokio::test]
+  async fn test_pack_dir() {
+    let root = scratch("rftp-test-pack-dir");
+    let folder = root.join("files");
+    std::fs::create_dir_all(folder.join("proj/secret")).unwrap();
+    std::fs::create_dir_all(folder.join("proj/sub")).unwrap();
+    std::fs::write(folder.join("proj/a.txt"), b"hello\n").unwrap();
+    std::fs::write(folder.join("proj/hidden.txt"), b"hidden\n").unwrap();
+    std::fs::write(folder.join("proj/secret/key"), b"key\n").unwrap();
+    std::fs::write(root.join("outside.txt"), b"outside\n").unwrap();
+    std::os::unix::fs::symlink("/", folder.join("proj/escape")).unwrap();
+    std::os::unix::fs::symlink(root.join("outside.txt"), folder.join("proj/out.txt")).unwrap();
+    std::os::unix::fs::symlink("a.txt", folder.join("proj/link.txt")).unwrap();
+    std::os::unix::fs::symlink("..", folder.join("proj/sub/loop")).unwrap();
+    let config = root.join("config.toml");
+    std::fs::write(
+      &config,
+      "archive_dirs = true\n\
+       [[acl]]\npath = \"/proj/secret\"\nuser = \"anonymous\"\ndeny = [\"list\"]\n\
+       [[acl]]\npath = \"/proj/hidden.txt\"\nuser = \"anonymous\"\ndeny = [\"read\"]\n",
+    )
+    .unwrap();
+
+    let addr = start(
+      &folder,
+      &["--anonymous", "--config", config.to_str().unwrap()],
+    )
+    .await;
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+    client.cmd("TYPE I").await;
+    let mut data = client.passive().await;
+    assert!(client.cmd("RETR proj.tar.gz").await.starts_with("150 "));
+    let mut packed = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_end(&mut packed))
+      .await
+      .expect("no data")
+      .unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+
+    let mut archive = tar::Archive::new(GzDecoder::new(packed.as_slice()));
+    let mut members = Vec::new();
+    for entry in archive.entries().unwrap() {
+      let mut entry = entry.unwrap();
+      let name = entry.path().unwrap().to_string_lossy().to_string();
+      let mut content = String::new();
+      std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+      members.push((name, content));
+    }
+    let members: Vec<(&str, &str)> = members
+      .iter()
+      .map(|(name, content)| (name.as_str(), content.as_str()))
+      .collect();
+    assert_eq!(
+      members,
+      [
+        ("proj", ""),
+        ("proj/a.txt", "hello\n"),
+        ("proj/link.txt", "hello\n"),
+        ("proj/sub", ""),
+      ]
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_abort_without_transfer() {
+    let root = scratch("rftp-test-abort");
+    let users = users_file(&root, "[\"read\", \"list\"]");
+    let addr = start(&root, &["--users", users.to_str().unwrap()]).await;
+    let mut client = Client::connect(addr).await;
+
+    // `ABOR` right behind the slow `PASS` waits for it instead of cutting it
+    // short.
+    assert!(client.cmd("USER alice").await.starts_with("331 "));
+    client.send("PASS secret\r\nABOR").await;
+    assert!(client.reply().await.starts_with("230 "));
+    assert!(client.reply().await.starts_with("226 "));
+    assert!(client.cmd("PWD").await.starts_with("257 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_close_during_transfer() {
+    let root = scratch("rftp-test-close");
+    let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.join("big.bin"), &content).unwrap();
+    let addr = start(&root, &["--anonymous"]).await;
+
+    // The client stops sending right after `RETR`, the download still
+    // completes.
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+    client.cmd("TYPE I").await;
+    let mut data = client.passive().await;
+    client.send("RETR big.bin").await;
+    client.writer.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_end(&mut received))
+      .await
+      .expect("no data")
+      .unwrap();
+    assert!(received == content);
+    assert!(client.reply().await.starts_with("150 "));
+    assert!(client.reply().await.starts_with("226 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_read_only_account() {
+    let root = scratch("rftp-test-read-only");
+    let folder = root.join("files");
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::write(folder.join("a.txt"), b"hello\n").unwrap();
+    let users = users_file(&root, "[\"read\", \"list\"]");
+    let addr = start(&folder, &["--users", users.to_str().unwrap()]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("alice", "secret").await;
+
+    for cmd in ["STOR b.txt", "DELE a.txt", "MKD d", "RNFR a.txt"] {
+      assert_eq!(client.cmd(cmd).await, "550 Permission denied.", "{}", cmd);
+    }
+    client.cmd("TYPE I").await;
+    assert_eq!(client.cmd("SIZE a.txt").await, "213 6");
+    let mut names = std::fs::read_dir(&folder)
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name())
+      .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a.txt"]);
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_hash_range() {
+    let root = scratch("rftp-test-hash-range");
+    std::fs::write(root.join("digits.txt"), b"0123456789").unwrap();
+    std::fs::write(root.join("empty.txt"), b"").unwrap();
+    let addr = start(&root, &["--anonymous"]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+
+    let whole = "84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882";
+    assert_eq!(
+      client.cmd("HASH digits.txt").await,
+      format!("213 SHA-256 0-9 {} digits.txt", whole)
+    );
+    assert!(client.cmd("RANG 0 9").await.starts_with("350 "));
+    assert_eq!(
+      client.cmd("HASH digits.txt").await,
+      format!("213 SHA-256 0-9 {} digits.txt", whole)
+    );
+    // The end byte is inclusive and clamped to the last byte of the file.
+    assert!(client.cmd("RANG 2 4").await.starts_with("350 "));
+    assert_eq!(
+      client.cmd("HASH digits.txt").await,
+      "213 SHA-256 2-4 114bd151f8fb0c58642d2170da4ae7d7c57977260ac2cc8905306cab6b2acabc digits.txt"
+    );
+    assert!(client.cmd("RANG 0 100").await.starts_with("350 "));
+    assert_eq!(
+      client.cmd("HASH digits.txt").await,
+      format!("213 SHA-256 0-9 {} digits.txt", whole)
+    );
+    assert!(client.cmd("RANG 10 20").await.starts_with("350 "));
+    assert!(client.cmd("HASH digits.txt").await.starts_with("556 "));
+    assert!(client
+      .cmd("HASH empty.txt")
+      .await
+      .starts_with("213 SHA-256 0-0 e3b0c442"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_auth_refused_keeps_pipeline() {
+    let root = scratch("rftp-test-auth-refused");
+    let addr = start(&root, &["--anonymous"]).await;
+    let mut client = Client::connect(addr).await;
+    client.send("AUTH TLS\r\nNOOP").await;
+    assert!(client.reply().await.starts_with("502 "));
+    assert!(client.reply().await.starts_with("200 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_auth_tls() {
+    let root = scratch("rftp-test-auth-tls");
+    std::fs::write(root.join("a.txt"), b"hello\n").unwrap();
+    let (addr, _) = start_tls(&root, &["--anonymous"]).await;
+    let config = client_tls();
+    let client = Client::connect(addr).await;
+    let mut client = client.auth_tls(&config).await;
+    client.login("anonymous", "guest").await;
+    assert!(client.cmd("PROT P").await.starts_with("503 "));
+    assert!(client.cmd("PBSZ 0").await.starts_with("200 "));
+    assert!(client.cmd("PROT P").await.starts_with("200 "));
+
+    let data = client.passive().await;
+    assert!(client.cmd("LIST").await.starts_with("150 "));
+    let mut data = tls_connect(&config, data).await.unwrap();
+    let mut list = String::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_string(&mut list))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(list.contains(" a.txt\r\n"));
+    assert!(client.reply().await.starts_with("226 "));
+
+    // `PROT C` goes back to plain data connections.
+    assert!(client.cmd("PROT C").await.starts_with("200 "));
+    let mut data = client.passive().await;
+    assert!(client.cmd("LIST").await.starts_with("150 "));
+    let mut list = String::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_string(&mut list))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(list.contains(" a.txt\r\n"));
+    assert!(client.reply().await.starts_with("226 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_tls_policy() {
+    let root = scratch("rftp-test-tls-policy");
+    std::fs::write(root.join("a.txt"), b"hello\n").unwrap();
+    let (addr, _) = start_tls(
+      &root,
+      &[
+        "--anonymous",
+        "--require-tls",
+        "--require-prot-p",
+        "--require-tls-reuse",
+      ],
+    )
+    .await;
+
+    let mut client = Client::connect(addr).await;
+    assert!(client.cmd("USER anonymous").await.starts_with("530 "));
+
+    let config = client_tls();
+    let mut client = client.auth_tls(&config).await;
+    client.login("anonymous", "guest").await;
+    assert!(client.cmd("PBSZ 0").await.starts_with("200 "));
+    assert!(client.cmd("LIST").await.starts_with("521 "));
+    assert!(client.cmd("PROT P").await.starts_with("200 "));
+
+    // The data connection resumes the session of the control connection.
+    let data = client.passive().await;
+    assert!(client.cmd("LIST").await.starts_with("150 "));
+    let mut data = tls_connect(&config, data).await.unwrap();
+    let mut list = String::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_string(&mut list))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(list.contains(" a.txt\r\n"));
+    assert!(client.reply().await.starts_with("226 "));
+
+    // A fresh handshake could come from anybody.
+    let data = client.passive().await;
+    assert!(client.cmd("LIST").await.starts_with("150 "));
+    if let Ok(mut data) = tls_connect(&client_tls(), data).await {
+      let mut list = String::new();
+      let read = data.read_to_string(&mut list);
+      let _ = tokio::time::timeout(Duration::from_secs(10), read)
+        .await
+        .unwrap();
+      assert!(list.is_empty());
+    }
+    assert!(client.reply().await.starts_with("550 "));
+    assert!(client.cmd("NOOP").await.starts_with("200 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_implicit_tls() {
+    let root = scratch("rftp-test-implicit-tls");
+    let (_, implicit) = start_tls(&root, &["--anonymous", "--require-tls"]).await;
+    let mut client = Client::connect_tls(implicit, &client_tls()).await;
+    // The connection is protected from the start, so `AUTH` is not needed.
+    assert!(client.cmd("AUTH TLS").await.starts_with("503 "));
+    client.login("anonymous", "guest").await;
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_modify_time_round_trip() {
+    let root = scratch("rftp-test-mdtm");
+    std::fs::write(root.join("a.txt"), b"hello\n").unwrap();
+    let users = users_file(&root, "[\"read\", \"list\", \"write\"]");
+    let addr = start(&root, &["--users", users.to_str().unwrap()]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("alice", "secret").await;
+    assert!(client
+      .cmd("MFMT 20240102030405 a.txt")
+      .await
+      .starts_with("213 "));
+    assert_eq!(client.cmd("MDTM a.txt").await, "213 20240102030405");
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_deflate_mode() {
+    let root = scratch("rftp-test-mode-z");
+    let content = "All work and no play makes Jack a dull boy.\n".repeat(2000);
+    std::fs::write(root.join("a.txt"), &content).unwrap();
+    let users = users_file(&root, "[\"read\", \"list\", \"write\"]");
+    let addr = start(&root, &["--users", users.to_str().unwrap()]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("alice", "secret").await;
+    client.cmd("TYPE I").await;
+    assert!(client.cmd("OPTS MODE Z LEVEL 9").await.starts_with("200 "));
+    assert!(client.cmd("MODE Z").await.starts_with("200 "));
+
+    let mut data = client.passive().await;
+    assert!(client.cmd("RETR a.txt").await.starts_with("150 "));
+    let mut compressed = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_end(&mut compressed))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+    assert!(compressed.len() < content.len() / 10);
+    let mut inflated = String::new();
+    let mut decoder = flate2::read::ZlibDecoder::new(&compressed[..]);
+    std::io::Read::read_to_string(&mut decoder, &mut inflated).unwrap();
+    assert_eq!(inflated, content);
+
+    let mut data = client.passive().await;
+    assert!(client.cmd("STOR b.txt").await.starts_with("150 "));
+    data.write_all(&compressed).await.unwrap();
+    data.shutdown().await.unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+    assert_eq!(
+      std::fs::read_to_string(root.join("b.txt")).unwrap(),
+      content
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  /// Names listed by `NLST`.
+  async fn name_list(client: &mut Client) -> String {
+    let mut data = client.passive().await;
+    assert!(client.cmd("NLST").await.starts_with("150 "));
+    let mut list = String::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_string(&mut list))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+    list
+  }
+
+  #[tokio::test]
+  async fn test_home_jail() {
+    let root = scratch("rftp-test-home-jail");
+    let (folder, homes, anonymous) = (root.join("files"), root.join("homes"), root.join("pub"));
+    std::fs::create_dir_all(homes.join("alice")).unwrap();
+    std::fs::create_dir_all(homes.join("bob")).unwrap();
+    std::fs::create_dir_all(&folder).unwrap();
+    std::fs::create_dir_all(&anonymous).unwrap();
+    std::fs::write(homes.join("alice/mine.txt"), b"alice\n").unwrap();
+    std::fs::write(homes.join("bob/secret.txt"), b"bob\n").unwrap();
+    std::fs::write(folder.join("shared.txt"), b"shared\n").unwrap();
+    std::fs::write(anonymous.join("welcome.txt"), b"welcome\n").unwrap();
+    let users = users_file(&root, "[\"read\", \"list\"]");
+    let addr = start(
+      &folder,
+      &[
+        "--users",
+        users.to_str().unwrap(),
+        "--homes",
+        homes.to_str().unwrap(),
+        "--anonymous",
+        "--anonymous-root",
+        anonymous.to_str().unwrap(),
+      ],
+    )
+    .await;
+
+    let mut client = Client::connect(addr).await;
+    client.login("alice", "secret").await;
+    client.cmd("CWD ..").await;
+    assert_eq!(
+      client.cmd("PWD").await,
+      "257 \"/\" is the current directory."
+    );
+    assert_eq!(name_list(&mut client).await, "mine.txt\r\n");
+    let _data = client.passive().await;
+    assert!(client
+      .cmd("RETR ../bob/secret.txt")
+      .await
+      .starts_with("550 "));
+    assert!(client
+      .cmd("RETR /../bob/secret.txt")
+      .await
+      .starts_with("550 "));
+
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+    assert_eq!(name_list(&mut client).await, "welcome.txt\r\n");
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_active_mode() {
+    let root = scratch("rftp-test-eprt");
+    std::fs::write(root.join("a.txt"), b"hello\n").unwrap();
+    let addr = start(&root, &["--anonymous"]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+
+    assert_eq!(
+      client.cmd("EPRT |2|::1|1025|").await,
+      "522 Network protocol not supported, use (1)"
+    );
+    assert!(client.cmd("EPRT |3|x|1025|").await.starts_with("522 "));
+    assert!(client
+      .cmd("EPRT |1|192.0.2.1|1025|")
+      .await
+      .starts_with("504 "));
+    assert!(client.cmd("PORT 192,0,2,1,4,1").await.starts_with("504 "));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let eprt = format!("EPRT |1|127.0.0.1|{}|", port);
+    assert!(client.cmd(&eprt).await.starts_with("200 "));
+    let (mut data, _) = listener.accept().await.unwrap();
+    assert!(client.cmd("NLST").await.starts_with("150 "));
+    let mut list = String::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_string(&mut list))
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(list, "a.txt\r\n");
+    assert!(client.reply().await.starts_with("226 "));
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_ascii_size() {
+    let root = scratch("rftp-test-ascii-size");
+    std::fs::write(root.join("a.txt"), b"one\ntwo\r\nthree\n").unwrap();
+    let large = std::fs::File::create(root.join("large.bin")).unwrap();
+    large.set_len(64 * 1024 * 1024 + 1).unwrap();
+    let addr = start(&root, &["--anonymous"]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("anonymous", "guest").await;
+
+    assert!(client.cmd("TYPE A").await.starts_with("200 "));
+    // Bare line feeds go out as `\r\n`, the one already there stays as is.
+    assert_eq!(client.cmd("SIZE a.txt").await, "213 17");
+    let mut data = client.passive().await;
+    assert!(client.cmd("RETR a.txt").await.starts_with("150 "));
+    let mut sent = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), data.read_to_end(&mut sent))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+    assert_eq!(sent, b"one\r\ntwo\r\nthree\r\n");
+
+    assert!(client.cmd("SIZE large.bin").await.starts_with("550 "));
+    client.cmd("TYPE I").await;
+    assert_eq!(client.cmd("SIZE a.txt").await, "213 15");
+    assert_eq!(client.cmd("SIZE large.bin").await, "213 67108865");
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  /// Uploads `content` as `file_name`, returning the final reply.
+  async fn upload(client: &mut Client, file_name: &str, content: &[u8]) -> String {
+    let mut data = client.passive().await;
+    let reply = client.cmd(&format!("STOR {}", file_name)).await;
+    if !reply.starts_with("150 ") {
+      return reply;
+    }
+    data.write_all(content).await.unwrap();
+    data.shutdown().await.unwrap();
+    client.reply().await
+  }
+
+  #[tokio::test]
+  async fn test_quota() {
+    let root = scratch("rftp-test-quota");
+    let folder = root.join("files");
+    std::fs::create_dir_all(folder.join("sub")).unwrap();
+    std::fs::write(folder.join("sub/old.txt"), b"0123").unwrap();
+    let users = root.join("users.toml");
+    let hash = auth::hash_password("secret").unwrap();
+    std::fs::write(
+      &users,
+      format!(
+        "[[users]]\nusername = \"alice\"\npassword = \"{}\"\nquota = 20\n",
+        hash
+      ),
+    )
+    .unwrap();
+    let addr = start(&folder, &["--users", users.to_str().unwrap()]).await;
+    let mut client = Client::connect(addr).await;
+    client.login("alice", "secret").await;
+    client.cmd("TYPE I").await;
+
+    let exceeded = "552 Transfer aborted, exceeded storage allocation.";
+    assert!(upload(&mut client, "a.txt", b"012345")
+      .await
+      .starts_with("226 "));
+    assert!(upload(&mut client, "b.txt", b"012345")
+      .await
+      .starts_with("226 "));
+    assert_eq!(upload(&mut client, "c.txt", b"012345").await, exceeded);
+    // Rewriting the end of a file only counts what it grows by.
+    let mut data = client.passive().await;
+    assert!(client.cmd("REST 4").await.starts_with("350 "));
+    assert!(client.cmd("STOR a.txt").await.starts_with("150 "));
+    data.write_all(b"45").await.unwrap();
+    data.shutdown().await.unwrap();
+    assert!(client.reply().await.starts_with("226 "));
+    assert!(client.cmd("DELE c.txt").await.starts_with("250 "));
+    assert!(client.cmd("DELE sub/old.txt").await.starts_with("250 "));
+    assert!(upload(&mut client, "c.txt", b"01234567")
+      .await
+      .starts_with("226 "));
+    assert_eq!(
+      upload(&mut client, "d.txt", b"0").await,
+      "552 Exceeded storage allocation."
+    );
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -1,12 +1,27 @@
+use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tokio::sync::Mutex;
 
+use crate::lib::stream::{BoxedStream, DeflateStream};
+
+/// How long a transfer waits for the client to open the data connection.
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub enum TransferMode {
-  Port(Arc<Mutex<TcpStream>>),
-  Passive(Arc<Mutex<TcpStream>>),
+  Port,
+  Passive,
+}
+
+/// The data connection, which is still being opened (and possibly wrapped in
+/// TLS) by a background task until the first transfer needs it.
+#[derive(Debug)]
+enum DataStream {
+  Pending(oneshot::Receiver<BoxedStream>),
+  Ready(Arc<Mutex<BoxedStream>>),
 }
 
 #[derive(Debug)]
@@ -16,26 +31,53 @@ pub struct TransferSession {
   pub finished_size: u64,
   pub file_name: String,
   pub finished: bool,
-  pub aborted: bool,
   pub offset: u64,
+  /// Compression level of a `MODE Z` transfer.
+  pub deflate_level: Option<u32>,
+
+  stream: DataStream,
 }
 
 impl TransferSession {
-  pub fn new(mode: TransferMode) -> Self {
+  pub fn new(mode: TransferMode, stream: oneshot::Receiver<BoxedStream>) -> Self {
     Self {
       mode,
       total_size: 0,
       finished_size: 0,
       file_name: String::new(),
       finished: false,
-      aborted: false,
       offset: 0,
+      deflate_level: None,
+      stream: DataStream::Pending(stream),
     }
   }
-  pub fn get_stream(&self) -> Arc<Mutex<TcpStream>> {
-    match &self.mode {
-      TransferMode::Port(stream) => stream.clone(),
-      TransferMode::Passive(stream) => stream.clone(),
+
+  /// Starts a transfer of `file_name`. In block mode one data connection
+  /// carries several transfers. Returns the `REST` offset, which only applies
+  /// to this transfer.
+  pub fn start(&mut self, file_name: &str) -> u64 {
+    self.file_name = file_name.to_string();
+    self.total_size = 0;
+    self.finished_size = 0;
+    self.finished = false;
+    std::mem::take(&mut self.offset)
+  }
+
+  pub async fn get_stream(&mut self) -> Result<Arc<Mutex<BoxedStream>>, Box<dyn Error>> {
+    if let DataStream::Pending(receiver) = &mut self.stream {
+      let stream = match tokio::time::timeout(DATA_CONNECTION_TIMEOUT, receiver).await {
+        Ok(Ok(stream)) => stream,
+        _ => return Err("Can't open data connection".into()),
+      };
+      let stream = match self.deflate_level {
+        Some(level) => Box::new(DeflateStream::new(stream, level)),
+        None => stream,
+      };
+      self.stream = DataStream::Ready(Arc::new(Mutex::new(stream)));
+    }
+    match &self.stream {
+      DataStream::Ready(stream) => Ok(stream.clone()),
+      DataStream::Pending(_) => Err("Can't open data connection".into()),
     }
   }
 }
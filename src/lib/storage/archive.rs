@@ -0,0 +1,821 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use super::{ChunkReader, DirEntry, FileReader, FileWriter, Metadata, StorageBackend};
+use crate::lib::mfxx::Change;
+
+/// Archive indexes kept in memory.
+const INDEX_CACHE_SIZE: usize = 32;
+
+/// Size of the chunks members are unpacked in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Forward seeks up to this distance read on instead of reopening the file,
+/// which is much cheaper for object stores.
+const SKIP_LIMIT: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+  Zip,
+  Tar,
+  TarGz,
+}
+
+impl ArchiveKind {
+  fn of(path: &Path) -> Option<Self> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+      Some(Self::Zip)
+    } else if name.ends_with(".tar") {
+      Some(Self::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+      Some(Self::TarGz)
+    } else {
+      None
+    }
+  }
+}
+
+/// Where the data of a member is.
+#[derive(Debug, Clone, Copy)]
+enum Location {
+  Dir,
+  /// Index of the member in a zip archive.
+  Zip(usize),
+  /// Offset of the data in an uncompressed tar archive.
+  Tar(u64),
+  /// Position of the entry in a compressed tar archive, which has to be
+  /// unpacked up to it.
+  TarGz(usize),
+}
+
+#[derive(Debug)]
+struct Member {
+  metadata: Metadata,
+  location: Location,
+}
+
+/// Members of an archive by their path inside it, their parents included.
+#[derive(Debug)]
+struct Index {
+  /// Size and time of the archive when it was indexed.
+  len: u64,
+  modified: Option<SystemTime>,
+  members: BTreeMap<PathBuf, Member>,
+}
+
+/// Serves the members of archives on top of another storage, so that e.g.
+/// `release.tar.gz/docs/README` can be listed and retrieved without unpacking
+/// the archive anywhere. Archives stay files, flagged with
+/// `Metadata::archive`, and what is inside them is read-only.
+#[derive(Debug)]
+pub struct ArchiveBackend {
+  inner: Arc<dyn StorageBackend>,
+  /// Served folder the `patterns` are matched below.
+  root: PathBuf,
+  /// Archives that can be browsed, all of them if `None`.
+  patterns: Option<GlobSet>,
+  indexes: Mutex<HashMap<PathBuf, Arc<Index>>>,
+}
+
+impl ArchiveBackend {
+  /// Browses the archives below `root` matching one of `patterns`, globs
+  /// over paths like `/releases/*.tar.gz`, or any archive if `None`.
+  pub fn new(
+    inner: Arc<dyn StorageBackend>,
+    root: PathBuf,
+    patterns: Option<&[String]>,
+  ) -> Result<Self, Box<dyn Error>> {
+    let patterns = match patterns {
+      Some(patterns) => {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+          builder.add(
+            Glob::new(pattern).map_err(|e| format!("Invalid archive path {}: {}", pattern, e))?,
+          );
+        }
+        Some(builder.build()?)
+      }
+      None => None,
+    };
+    Ok(Self {
+      inner,
+      root,
+      patterns,
+      indexes: Mutex::new(HashMap::new()),
+    })
+  }
+
+  fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, Arc<Index>>> {
+    self.indexes.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  fn browsable(&self, path: &Path) -> bool {
+    if ArchiveKind::of(path).is_none() {
+      return false;
+    }
+    match &self.patterns {
+      Some(patterns) => path
+        .strip_prefix(&self.root)
+        .is_ok_and(|path| patterns.is_match(Path::new("/").join(path))),
+      None => true,
+    }
+  }
+
+  /// Splits `path` into the archive it leads through, the metadata of that
+  /// archive and the path of the member inside it, which is empty for the
+  /// archive itself.
+  async fn split(&self, path: &Path) -> Option<(PathBuf, Metadata, PathBuf)> {
+    let mut archive = PathBuf::new();
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+      archive.push(component);
+      if !self.browsable(&archive) {
+        continue;
+      }
+      match self.inner.metadata(&archive).await {
+        Ok(metadata) if !metadata.is_dir => {
+          return Some((archive, metadata, components.as_path().to_path_buf()));
+        }
+        _ => {}
+      }
+    }
+    None
+  }
+
+  /// Refuses to change what is inside an archive.
+  async fn check_writable(&self, path: &Path) -> io::Result<()> {
+    match self.split(path).await {
+      Some((_, _, member)) if !member.as_os_str().is_empty() => Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Archives are read-only",
+      )),
+      _ => Ok(()),
+    }
+  }
+
+  fn blocking_file(&self, archive: &Path, len: u64) -> BlockingFile {
+    BlockingFile::new(self.inner.clone(), archive.to_path_buf(), len)
+  }
+
+  /// Index of `archive`, read again if it changed since it was cached.
+  async fn index(&self, archive: &Path, metadata: &Metadata) -> io::Result<Arc<Index>> {
+    if let Some(index) = self.lock().get(archive) {
+      if index.len == metadata.len && index.modified == metadata.modified {
+        return Ok(index.clone());
+      }
+    }
+
+    let kind = ArchiveKind::of(archive).ok_or(io::ErrorKind::InvalidInput)?;
+    let file = self.blocking_file(archive, metadata.len);
+    let members = tokio::task::spawn_blocking(move || match kind {
+      ArchiveKind::Zip => index_zip(file),
+      ArchiveKind::Tar | ArchiveKind::TarGz => index_tar(file, kind),
+    })
+    .await??;
+    let index = Arc::new(Index {
+      len: metadata.len,
+      modified: metadata.modified,
+      members,
+    });
+
+    let mut indexes = self.lock();
+    if indexes.len() >= INDEX_CACHE_SIZE {
+      if let Some(evicted) = indexes.keys().next().cloned() {
+        indexes.remove(&evicted);
+      }
+    }
+    indexes.insert(archive.to_path_buf(), index.clone());
+    Ok(index)
+  }
+}
+
+#[async_trait]
+impl StorageBackend for ArchiveBackend {
+  fn resolve(&self, root: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    self.inner.resolve(root, path)
+  }
+
+  async fn prepare_root(&self, path: &Path) -> io::Result<PathBuf> {
+    self.inner.prepare_root(path).await
+  }
+
+  async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+    let (archive, metadata, member) = match self.split(path).await {
+      Some(split) => split,
+      None => return self.inner.metadata(path).await,
+    };
+    if member.as_os_str().is_empty() {
+      return Ok(Metadata {
+        archive: true,
+        ..metadata
+      });
+    }
+    let index = self.index(&archive, &metadata).await?;
+    match index.members.get(&member) {
+      Some(member) => Ok(member.metadata.clone()),
+      None => Err(io::ErrorKind::NotFound.into()),
+    }
+  }
+
+  async fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+    let (archive, metadata, member) = match self.split(path).await {
+      Some(split) => split,
+      None => {
+        let mut entries = self.inner.list(path).await?;
+        for entry in &mut entries {
+          entry.metadata.archive =
+            !entry.metadata.is_dir && self.browsable(&path.join(&entry.name));
+        }
+        return Ok(entries);
+      }
+    };
+    let index = self.index(&archive, &metadata).await?;
+    if !member.as_os_str().is_empty()
+      && !index
+        .members
+        .get(&member)
+        .is_some_and(|member| member.metadata.is_dir)
+    {
+      return Err(io::ErrorKind::NotADirectory.into());
+    }
+    Ok(
+      index
+        .members
+        .iter()
+        .filter(|(path, _)| path.parent() == Some(&member))
+        .filter_map(|(path, member)| {
+          Some(DirEntry {
+            name: path.file_name()?.to_os_string(),
+            metadata: member.metadata.clone(),
+          })
+        })
+        .collect(),
+    )
+  }
+
+  async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+    let (archive, metadata, member) = match self.split(path).await {
+      Some(split) if !split.2.as_os_str().is_empty() => split,
+      _ => return self.inner.open_read(path, offset).await,
+    };
+    let index = self.index(&archive, &metadata).await?;
+    let member = index.members.get(&member).ok_or(io::ErrorKind::NotFound)?;
+    let len = member.metadata.len;
+    let file = self.blocking_file(&archive, metadata.len);
+    match member.location {
+      Location::Dir => Err(io::ErrorKind::IsADirectory.into()),
+      Location::Tar(start) => {
+        let reader = self.inner.open_read(&archive, start + offset).await?;
+        Ok(Box::new(reader.take(len.saturating_sub(offset))))
+      }
+      Location::Zip(i) => Ok(spawn_producer(move |sender| {
+        let mut zip = ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+        let member = zip.by_index(i).map_err(io::Error::other)?;
+        send_member(member, offset, sender)
+      })),
+      Location::TarGz(i) => Ok(spawn_producer(move |sender| {
+        let mut tar = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+        let member = tar.entries()?.nth(i).ok_or(io::ErrorKind::NotFound)??;
+        send_member(member, offset, sender)
+      })),
+    }
+  }
+
+  async fn open_write(&self, path: &Path, offset: Option<u64>) -> io::Result<FileWriter> {
+    self.check_writable(path).await?;
+    self.inner.open_write(path, offset).await
+  }
+
+  async fn create_dir(&self, path: &Path) -> io::Result<()> {
+    self.check_writable(path).await?;
+    self.inner.create_dir(path).await
+  }
+
+  async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+    self.check_writable(path).await?;
+    self.inner.remove_dir(path).await
+  }
+
+  async fn remove_file(&self, path: &Path) -> io::Result<()> {
+    self.check_writable(path).await?;
+    self.inner.remove_file(path).await
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+    self.check_writable(from).await?;
+    self.check_writable(to).await?;
+    self.inner.rename(from, to).await
+  }
+
+  async fn set_facts(&self, path: &Path, changes: &[Change]) -> io::Result<()> {
+    self.check_writable(path).await?;
+    self.inner.set_facts(path, changes).await
+  }
+}
+
+/// `path` inside an archive without `.`, or `None` if it could lead out.
+fn member_path(path: &Path) -> Option<PathBuf> {
+  let mut member = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => member.push(name),
+      Component::CurDir => {}
+      _ => return None,
+    }
+  }
+  (!member.as_os_str().is_empty()).then_some(member)
+}
+
+/// Adds a member and the directories above it, which archives may leave
+/// out.
+fn add_member(
+  members: &mut BTreeMap<PathBuf, Member>,
+  path: &Path,
+  metadata: Metadata,
+  location: Location,
+) {
+  let path = match member_path(path) {
+    Some(path) => path,
+    None => return,
+  };
+  for parent in path.ancestors().skip(1) {
+    if parent.as_os_str().is_empty() {
+      break;
+    }
+    members.entry(parent.to_path_buf()).or_insert(Member {
+      metadata: Metadata {
+        is_dir: true,
+        ..Metadata::default()
+      },
+      location: Location::Dir,
+    });
+  }
+  members.insert(path, Member { metadata, location });
+}
+
+fn index_zip(file: BlockingFile) -> io::Result<BTreeMap<PathBuf, Member>> {
+  let mut zip = ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+  let mut members = BTreeMap::new();
+  for i in 0..zip.len() {
+    let entry = zip.by_index_raw(i).map_err(io::Error::other)?;
+    let path = match entry.enclosed_name() {
+      Some(path) => path,
+      None => continue,
+    };
+    // Zip archives store the local time of the machine that made them.
+    let modified = entry
+      .last_modified()
+      .and_then(|time| NaiveDateTime::try_from(time).ok())
+      .and_then(|time| Local.from_local_datetime(&time).earliest())
+      .map(SystemTime::from);
+    let metadata = Metadata {
+      is_dir: entry.is_dir(),
+      len: if entry.is_dir() { 0 } else { entry.size() },
+      modified,
+      mode: entry.unix_mode().map(|mode| mode & 0o7777),
+      unique: None,
+      archive: false,
+    };
+    let location = if entry.is_dir() {
+      Location::Dir
+    } else {
+      Location::Zip(i)
+    };
+    add_member(&mut members, &path, metadata, location);
+  }
+  Ok(members)
+}
+
+fn index_tar(file: BlockingFile, kind: ArchiveKind) -> io::Result<BTreeMap<PathBuf, Member>> {
+  let mut members = BTreeMap::new();
+  let file = BufReader::new(file);
+  if kind == ArchiveKind::TarGz {
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    add_tar_entries(&mut members, tar.entries()?, true)?;
+  } else {
+    // Uncompressed archives are read header by header, seeking over data.
+    let mut tar = tar::Archive::new(file);
+    add_tar_entries(&mut members, tar.entries_with_seek()?, false)?;
+  }
+  Ok(members)
+}
+
+fn add_tar_entries<R: Read>(
+  members: &mut BTreeMap<PathBuf, Member>,
+  entries: tar::Entries<R>,
+  compressed: bool,
+) -> io::Result<()> {
+  for (i, entry) in entries.enumerate() {
+    let entry = entry?;
+    let header = entry.header();
+    let is_dir = header.entry_type().is_dir();
+    // Links and special files are left out.
+    if !is_dir && !header.entry_type().is_file() {
+      continue;
+    }
+    let metadata = Metadata {
+      is_dir,
+      len: if is_dir { 0 } else { entry.size() },
+      modified: header
+        .mtime()
+        .ok()
+        .map(|time| UNIX_EPOCH + Duration::from_secs(time)),
+      mode: header.mode().ok().map(|mode| mode & 0o7777),
+      unique: None,
+      archive: false,
+    };
+    let location = match (is_dir, compressed) {
+      (true, _) => Location::Dir,
+      (false, true) => Location::TarGz(i),
+      (false, false) => Location::Tar(entry.raw_file_position()),
+    };
+    add_member(members, &entry.path()?, metadata, location);
+  }
+  Ok(())
+}
+
+type ChunkSender = mpsc::Sender<io::Result<Vec<u8>>>;
+
+/// Runs `produce` off the runtime and reads what it sends.
+fn spawn_producer(
+  produce: impl FnOnce(&ChunkSender) -> io::Result<()> + Send + 'static,
+) -> FileReader {
+  let (sender, reader) = ChunkReader::new();
+  tokio::task::spawn_blocking(move || {
+    if let Err(e) = produce(&sender) {
+      let _ = sender.blocking_send(Err(e));
+    }
+  });
+  Box::new(reader)
+}
+
+/// Sends the data of a member from `offset` on, until the reader is gone.
+fn send_member(mut member: impl Read, offset: u64, sender: &ChunkSender) -> io::Result<()> {
+  io::copy(&mut (&mut member).take(offset), &mut io::sink())?;
+  loop {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let n = member.read(&mut chunk)?;
+    if n == 0 {
+      return Ok(());
+    }
+    chunk.truncate(n);
+    if sender.blocking_send(Ok(chunk)).is_err() {
+      return Ok(());
+    }
+  }
+}
+
+/// Formats of the archives generated for whole directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackFormat {
+  Zip,
+  TarGz,
+}
+
+impl PackFormat {
+  /// The format named by the extension of `path`, and `path` without it.
+  pub fn strip(path: &str) -> Option<(Self, &str)> {
+    if let Some(dir) = path.strip_suffix(".zip") {
+      return Some((Self::Zip, dir));
+    }
+    path.strip_suffix(".tar.gz").map(|dir| (Self::TarGz, dir))
+  }
+}
+
+/// A file or directory of a generated archive.
+#[derive(Debug)]
+pub struct PackMember {
+  /// Where the member is kept in the storage.
+  pub path: PathBuf,
+  /// Path of the member inside the archive, separated by `/`.
+  pub name: String,
+  pub metadata: Metadata,
+}
+
+/// Generates an archive of `members` while it is read, without storing it
+/// anywhere.
+pub fn pack(
+  storage: Arc<dyn StorageBackend>,
+  format: PackFormat,
+  members: Vec<PackMember>,
+) -> FileReader {
+  spawn_producer(move |sender| {
+    let mut output = ChunkWriter {
+      sender,
+      chunk: Vec::new(),
+    };
+    match format {
+      PackFormat::Zip => pack_zip(&storage, &members, &mut output)?,
+      PackFormat::TarGz => pack_tar_gz(&storage, &members, &mut output)?,
+    }
+    output.flush()
+  })
+}
+
+/// Data of a file member, padded or cut to the size the archive headers
+/// already promised in case the file changed meanwhile.
+fn member_data(storage: &Arc<dyn StorageBackend>, member: &PackMember) -> impl Read {
+  let len = member.metadata.len;
+  BlockingFile::new(storage.clone(), member.path.clone(), len)
+    .chain(io::repeat(0))
+    .take(len)
+}
+
+fn pack_tar_gz(
+  storage: &Arc<dyn StorageBackend>,
+  members: &[PackMember],
+  output: &mut ChunkWriter,
+) -> io::Result<()> {
+  let mut tar = tar::Builder::new(GzEncoder::new(output, Compression::default()));
+  for member in members {
+    let mut header = tar::Header::new_gnu();
+    header.set_mtime(
+      member
+        .metadata
+        .modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_secs()),
+    );
+    if member.metadata.is_dir {
+      header.set_entry_type(tar::EntryType::Directory);
+      header.set_mode(member.metadata.mode.unwrap_or(0o755));
+      header.set_size(0);
+      tar.append_data(&mut header, &member.name, io::empty())?;
+    } else {
+      header.set_mode(member.metadata.mode.unwrap_or(0o644));
+      header.set_size(member.metadata.len);
+      tar.append_data(&mut header, &member.name, member_data(storage, member))?;
+    }
+  }
+  tar.into_inner()?.finish()?;
+  Ok(())
+}
+
+fn pack_zip(
+  storage: &Arc<dyn StorageBackend>,
+  members: &[PackMember],
+  output: &mut ChunkWriter,
+) -> io::Result<()> {
+  let mut zip = ZipWriter::new_stream(output);
+  for member in members {
+    let mut options = SimpleFileOptions::default()
+      .compression_method(CompressionMethod::Deflated)
+      .large_file(member.metadata.len > u32::MAX as u64);
+    let modified = member
+      .metadata
+      .modified
+      .and_then(|time| zip::DateTime::try_from(DateTime::<Local>::from(time).naive_local()).ok());
+    if let Some(modified) = modified {
+      options = options.last_modified_time(modified);
+    }
+    if let Some(mode) = member.metadata.mode {
+      options = options.unix_permissions(mode);
+    }
+    if member.metadata.is_dir {
+      zip
+        .add_directory(member.name.as_str(), options)
+        .map_err(io::Error::other)?;
+    } else {
+      zip
+        .start_file(member.name.as_str(), options)
+        .map_err(io::Error::other)?;
+      io::copy(&mut member_data(storage, member), &mut zip)?;
+    }
+  }
+  zip.finish().map_err(io::Error::other)?;
+  Ok(())
+}
+
+/// Sends what is written to a `ChunkReader`, in chunks of `CHUNK_SIZE`.
+struct ChunkWriter<'a> {
+  sender: &'a ChunkSender,
+  chunk: Vec<u8>,
+}
+
+impl Write for ChunkWriter<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.chunk.extend_from_slice(buf);
+    if self.chunk.len() >= CHUNK_SIZE {
+      self.flush()?;
+    }
+    Ok(buf.len())
+  }
+
+  /// Fails once the reader is gone, which stops the archive.
+  fn flush(&mut self) -> io::Result<()> {
+    if self.chunk.is_empty() {
+      return Ok(());
+    }
+    let chunk = std::mem::take(&mut self.chunk);
+    self
+      .sender
+      .blocking_send(Ok(chunk))
+      .map_err(|_| io::ErrorKind::BrokenPipe.into())
+  }
+}
+
+/// A file of the inner storage with the blocking `Read` and `Seek` the
+/// archive crates want. Must only be used off the runtime.
+struct BlockingFile {
+  storage: Arc<dyn StorageBackend>,
+  path: PathBuf,
+  len: u64,
+  position: u64,
+  reader: Option<FileReader>,
+  handle: Handle,
+}
+
+impl BlockingFile {
+  fn new(storage: Arc<dyn StorageBackend>, path: PathBuf, len: u64) -> Self {
+    Self {
+      storage,
+      path,
+      len,
+      position: 0,
+      reader: None,
+      handle: Handle::current(),
+    }
+  }
+}
+
+impl Read for BlockingFile {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.reader.is_none() {
+      let reader = self
+        .handle
+        .block_on(self.storage.open_read(&self.path, self.position))?;
+      self.reader = Some(reader);
+    }
+    let reader = self.reader.as_mut().ok_or(io::ErrorKind::NotConnected)?;
+    let n = self.handle.block_on(reader.read(buf))?;
+    self.position += n as u64;
+    Ok(n)
+  }
+}
+
+impl Seek for BlockingFile {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let target = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+    }
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek"))?;
+
+    if let Some(reader) = self.reader.as_mut() {
+      if target >= self.position && target - self.position <= SKIP_LIMIT {
+        let mut skipped = reader.take(target - self.position);
+        self.position += self
+          .handle
+          .block_on(tokio::io::copy(&mut skipped, &mut tokio::io::sink()))?;
+      }
+    }
+    if self.position != target {
+      self.reader = None;
+      self.position = target;
+    }
+    Ok(self.position)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lib::storage::MemoryBackend;
+  use std::io::Cursor;
+  use tokio::io::AsyncWriteExt;
+
+  async fn store(storage: &dyn StorageBackend, path: &str, data: &[u8]) {
+    let mut file = storage.open_write(Path::new(path), None).await.unwrap();
+    file.write_all(data).await.unwrap();
+    file.shutdown().await.unwrap();
+  }
+
+  async fn read(storage: &dyn StorageBackend, path: &str, offset: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut file = storage.open_read(Path::new(path), offset).await.unwrap();
+    file.read_to_end(&mut data).await.unwrap();
+    data
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_archive_backend() {
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    tar
+      .append_data(&mut header, "docs/readme", &b"hello"[..])
+      .unwrap();
+    let tar = tar.into_inner().unwrap();
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&tar).unwrap();
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    zip
+      .start_file("bin/tool", zip::write::SimpleFileOptions::default())
+      .unwrap();
+    zip.write_all(b"binary").unwrap();
+    let zip = zip.finish().unwrap().into_inner();
+
+    let inner = Arc::new(MemoryBackend::default());
+    inner.create_dir(Path::new("/srv")).await.unwrap();
+    store(&*inner, "/srv/a.tar", &tar).await;
+    store(&*inner, "/srv/a.tar.gz", &gz.finish().unwrap()).await;
+    store(&*inner, "/srv/a.zip", &zip).await;
+    let patterns = ["/a.*".to_string()];
+    let storage = ArchiveBackend::new(inner, PathBuf::from("/srv"), Some(&patterns)).unwrap();
+
+    let entries = storage.list(Path::new("/srv")).await.unwrap();
+    assert!(entries.iter().all(|entry| entry.metadata.archive));
+    for archive in ["/srv/a.tar", "/srv/a.tar.gz"] {
+      let docs = storage
+        .list(&Path::new(archive).join("docs"))
+        .await
+        .unwrap();
+      assert_eq!(docs.len(), 1);
+      assert_eq!(docs[0].name, "readme");
+      assert_eq!(docs[0].metadata.len, 5);
+      let readme = format!("{}/docs/readme", archive);
+      assert_eq!(read(&storage, &readme, 0).await, b"hello");
+      assert_eq!(read(&storage, &readme, 2).await, b"llo");
+    }
+    assert!(
+      storage
+        .metadata(Path::new("/srv/a.zip/bin"))
+        .await
+        .unwrap()
+        .is_dir
+    );
+    assert_eq!(read(&storage, "/srv/a.zip/bin/tool", 3).await, b"ary");
+    assert!(storage
+      .metadata(Path::new("/srv/a.zip/nope"))
+      .await
+      .is_err());
+    assert!(storage
+      .remove_file(Path::new("/srv/a.zip/bin/tool"))
+      .await
+      .is_err());
+    assert_eq!(read(&storage, "/srv/a.tar", 0).await, tar);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_pack() {
+    let storage: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::default());
+    storage.create_dir(Path::new("/d")).await.unwrap();
+    store(&*storage, "/d/a", b"hello").await;
+    let member = |path: &str, name: &str, is_dir| PackMember {
+      path: PathBuf::from(path),
+      name: name.to_string(),
+      metadata: Metadata {
+        is_dir,
+        len: if is_dir { 0 } else { 5 },
+        ..Metadata::default()
+      },
+    };
+    let members = || vec![member("/d", "d", true), member("/d/a", "d/a", false)];
+
+    let mut zip = Vec::new();
+    pack(storage.clone(), PackFormat::Zip, members())
+      .read_to_end(&mut zip)
+      .await
+      .unwrap();
+    let mut zip = ZipArchive::new(Cursor::new(zip)).unwrap();
+    assert!(zip.by_name("d/").unwrap().is_dir());
+    let mut data = Vec::new();
+    zip.by_name("d/a").unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"hello");
+
+    let mut gz = Vec::new();
+    pack(storage, PackFormat::TarGz, members())
+      .read_to_end(&mut gz)
+      .await
+      .unwrap();
+    let mut tar = tar::Archive::new(GzDecoder::new(&gz[..]));
+    let names = tar
+      .entries()
+      .unwrap()
+      .map(|entry| entry.unwrap().path().unwrap().to_path_buf())
+      .collect::<Vec<_>>();
+    assert_eq!(names, [Path::new("d"), Path::new("d/a")]);
+    assert_eq!(
+      PackFormat::strip("/d.tar.gz"),
+      Some((PackFormat::TarGz, "/d"))
+    );
+  }
+}
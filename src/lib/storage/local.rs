@@ -0,0 +1,153 @@
+use async_trait::async_trait;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncSeekExt;
+
+use super::{DirEntry, FileReader, FileWriter, Metadata, StorageBackend};
+use crate::lib::charset::Charset;
+use crate::lib::mfxx::{self, Change};
+
+/// Files in the local file system, where storage paths are paths on disk.
+#[derive(Debug, Default)]
+pub struct LocalFsBackend {
+  /// Used to find names stored on disk in the fallback charset.
+  charset: Charset,
+}
+
+impl LocalFsBackend {
+  pub fn new(charset: Charset) -> Self {
+    Self { charset }
+  }
+}
+
+impl From<std::fs::Metadata> for Metadata {
+  fn from(metadata: std::fs::Metadata) -> Self {
+    Self {
+      is_dir: metadata.is_dir(),
+      len: metadata.len(),
+      modified: metadata.modified().ok(),
+      mode: Some(metadata.permissions().mode() & 0o7777),
+      unique: Some(format!("{:x}g{:x}", metadata.dev(), metadata.ino())),
+      archive: false,
+    }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for LocalFsBackend {
+  fn resolve(&self, root: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    let path = self.charset.disk_path(path);
+
+    // Symlinks are followed on the deepest existing ancestor so that they
+    // cannot lead out of the root.
+    let mut existing = path.as_path();
+    while !existing.exists() {
+      existing = existing
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+    }
+    if !existing.canonicalize()?.starts_with(root) {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Path not allowed",
+      ));
+    }
+    Ok(path)
+  }
+
+  async fn prepare_root(&self, path: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(path).await?;
+    fs::canonicalize(path).await
+  }
+
+  async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+    Ok(fs::metadata(path).await?.into())
+  }
+
+  async fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    let mut dir = fs::read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+      // Symlinks are not followed, so that walks over the listing can neither
+      // leave the root nor loop. Entries it cannot stat are skipped.
+      if let Ok(metadata) = fs::symlink_metadata(entry.path()).await {
+        entries.push(DirEntry {
+          name: entry.file_name(),
+          metadata: metadata.into(),
+        });
+      }
+    }
+    Ok(entries)
+  }
+
+  async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+    let mut file = File::open(path).await?;
+    if offset > 0 {
+      file.seek(SeekFrom::Start(offset)).await?;
+    }
+    Ok(Box::new(file))
+  }
+
+  async fn open_write(&self, path: &Path, offset: Option<u64>) -> io::Result<FileWriter> {
+    let file = match offset {
+      Some(offset) => {
+        let mut file = OpenOptions::new().write(true).open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file
+      }
+      None => File::create(path).await?,
+    };
+    Ok(Box::new(file))
+  }
+
+  async fn create_dir(&self, path: &Path) -> io::Result<()> {
+    fs::create_dir(path).await
+  }
+
+  async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+    fs::remove_dir(path).await
+  }
+
+  async fn remove_file(&self, path: &Path) -> io::Result<()> {
+    fs::remove_file(path).await
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to).await
+  }
+
+  async fn set_facts(&self, path: &Path, changes: &[Change]) -> io::Result<()> {
+    let path = path.to_path_buf();
+    let changes = changes.to_vec();
+    tokio::task::spawn_blocking(move || mfxx::apply(&path, &changes)).await?
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_list_symlinks() {
+    let root = std::env::temp_dir().join("rftp-test-local-list");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("a")).unwrap();
+    std::os::unix::fs::symlink("..", root.join("a/loop")).unwrap();
+    std::os::unix::fs::symlink("/", root.join("escape")).unwrap();
+
+    let storage = LocalFsBackend::default();
+    let mut entries = storage.list(&root).await.unwrap();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(entries[0].name, "a");
+    assert!(entries[0].metadata.is_dir);
+    assert_eq!(entries[1].name, "escape");
+    assert!(!entries[1].metadata.is_dir);
+    let entries = storage.list(&root.join("a")).await.unwrap();
+    assert_eq!(entries[0].name, "loop");
+    assert!(!entries[0].metadata.is_dir);
+
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+}
@@ -0,0 +1,374 @@
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{normalize, DirEntry, FileReader, FileWriter, Metadata, StorageBackend};
+use crate::lib::mfxx::{self, Change};
+
+#[derive(Debug)]
+struct Entry {
+  is_dir: bool,
+  data: Vec<u8>,
+  modified: SystemTime,
+  mode: u32,
+}
+
+impl Entry {
+  fn new(is_dir: bool) -> Self {
+    Self {
+      is_dir,
+      data: Vec::new(),
+      modified: SystemTime::now(),
+      mode: if is_dir { 0o755 } else { 0o644 },
+    }
+  }
+
+  fn metadata(&self) -> Metadata {
+    Metadata {
+      is_dir: self.is_dir,
+      len: self.data.len() as u64,
+      modified: Some(self.modified),
+      mode: Some(self.mode),
+      unique: None,
+      archive: false,
+    }
+  }
+}
+
+/// Files kept in memory and lost when the server stops, for tests and
+/// throwaway shares. Storage paths are absolute paths from `/`.
+#[derive(Debug)]
+pub struct MemoryBackend {
+  /// Entries by path. Open files keep their entry, like on disk they would
+  /// keep writing to a file that is deleted meanwhile.
+  entries: Mutex<BTreeMap<PathBuf, Arc<Mutex<Entry>>>>,
+}
+
+impl Default for MemoryBackend {
+  fn default() -> Self {
+    let root = Arc::new(Mutex::new(Entry::new(true)));
+    Self {
+      entries: Mutex::new(BTreeMap::from([(PathBuf::from("/"), root)])),
+    }
+  }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn not_found() -> io::Error {
+  io::ErrorKind::NotFound.into()
+}
+
+impl MemoryBackend {
+  fn entry(&self, path: &Path) -> io::Result<Arc<Mutex<Entry>>> {
+    lock(&self.entries).get(path).cloned().ok_or_else(not_found)
+  }
+
+  fn file(&self, path: &Path) -> io::Result<Arc<Mutex<Entry>>> {
+    let entry = self.entry(path)?;
+    if lock(&entry).is_dir {
+      return Err(io::ErrorKind::IsADirectory.into());
+    }
+    Ok(entry)
+  }
+
+  /// Fails unless the parent directory of `path` exists.
+  fn check_parent(entries: &BTreeMap<PathBuf, Arc<Mutex<Entry>>>, path: &Path) -> io::Result<()> {
+    let parent = path.parent().ok_or(io::ErrorKind::AlreadyExists)?;
+    match entries.get(parent) {
+      Some(entry) if lock(entry).is_dir => Ok(()),
+      Some(_) => Err(io::ErrorKind::NotADirectory.into()),
+      None => Err(not_found()),
+    }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+  fn resolve(&self, root: &Path, path: PathBuf) -> io::Result<PathBuf> {
+    if !path.starts_with(root) {
+      return Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "Path not allowed",
+      ));
+    }
+    Ok(path)
+  }
+
+  async fn prepare_root(&self, path: &Path) -> io::Result<PathBuf> {
+    let path = normalize(path);
+    let mut entries = lock(&self.entries);
+    for dir in path.ancestors() {
+      match entries.get(dir) {
+        Some(entry) if lock(entry).is_dir => break,
+        Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+        None => {
+          entries.insert(dir.to_path_buf(), Arc::new(Mutex::new(Entry::new(true))));
+        }
+      }
+    }
+    Ok(path)
+  }
+
+  async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+    let entry = self.entry(path)?;
+    let metadata = lock(&entry).metadata();
+    Ok(metadata)
+  }
+
+  async fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+    let entries = lock(&self.entries);
+    match entries.get(path) {
+      Some(entry) if lock(entry).is_dir => {}
+      Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+      None => return Err(not_found()),
+    }
+    Ok(
+      entries
+        .range(path.to_path_buf()..)
+        .take_while(|(child, _)| child.starts_with(path))
+        .filter(|(child, _)| child.parent() == Some(path))
+        .filter_map(|(child, entry)| {
+          Some(DirEntry {
+            name: child.file_name()?.to_os_string(),
+            metadata: lock(entry).metadata(),
+          })
+        })
+        .collect(),
+    )
+  }
+
+  async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader> {
+    Ok(Box::new(MemoryFile {
+      entry: self.file(path)?,
+      position: offset,
+    }))
+  }
+
+  async fn open_write(&self, path: &Path, offset: Option<u64>) -> io::Result<FileWriter> {
+    let (entry, position) = match offset {
+      Some(offset) => (self.file(path)?, offset),
+      None => {
+        let mut entries = lock(&self.entries);
+        Self::check_parent(&entries, path)?;
+        let entry = entries
+          .entry(path.to_path_buf())
+          .or_insert_with(|| Arc::new(Mutex::new(Entry::new(false))))
+          .clone();
+        let mut file = lock(&entry);
+        if file.is_dir {
+          return Err(io::ErrorKind::IsADirectory.into());
+        }
+        file.data.clear();
+        file.modified = SystemTime::now();
+        drop(file);
+        (entry, 0)
+      }
+    };
+    Ok(Box::new(MemoryFile { entry, position }))
+  }
+
+  async fn create_dir(&self, path: &Path) -> io::Result<()> {
+    let mut entries = lock(&self.entries);
+    if entries.contains_key(path) {
+      return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    Self::check_parent(&entries, path)?;
+    entries.insert(path.to_path_buf(), Arc::new(Mutex::new(Entry::new(true))));
+    Ok(())
+  }
+
+  async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+    let mut entries = lock(&self.entries);
+    match entries.get(path) {
+      Some(entry) if lock(entry).is_dir => {}
+      Some(_) => return Err(io::ErrorKind::NotADirectory.into()),
+      None => return Err(not_found()),
+    }
+    let mut below = entries.range(path.to_path_buf()..);
+    below.next();
+    if below
+      .next()
+      .is_some_and(|(child, _)| child.starts_with(path))
+    {
+      return Err(io::ErrorKind::DirectoryNotEmpty.into());
+    }
+    if path.parent().is_none() {
+      return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    entries.remove(path);
+    Ok(())
+  }
+
+  async fn remove_file(&self, path: &Path) -> io::Result<()> {
+    self.file(path)?;
+    lock(&self.entries).remove(path);
+    Ok(())
+  }
+
+  async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+    let mut entries = lock(&self.entries);
+    if !entries.contains_key(from) {
+      return Err(not_found());
+    }
+    if from == to {
+      return Ok(());
+    }
+    if to.starts_with(from) {
+      return Err(io::ErrorKind::InvalidInput.into());
+    }
+    Self::check_parent(&entries, to)?;
+    let is_dir = entries.get(from).is_some_and(|entry| lock(entry).is_dir);
+    if entries
+      .get(to)
+      .is_some_and(|entry| is_dir || lock(entry).is_dir)
+    {
+      return Err(io::ErrorKind::AlreadyExists.into());
+    }
+    // A directory takes everything below it along.
+    let moved = entries
+      .range(from.to_path_buf()..)
+      .take_while(|(path, _)| path.starts_with(from))
+      .map(|(path, _)| path.clone())
+      .collect::<Vec<_>>();
+    for path in moved {
+      if let Some(entry) = entries.remove(&path) {
+        let relative = path.strip_prefix(from).unwrap_or(Path::new(""));
+        entries.insert(to.join(relative), entry);
+      }
+    }
+    Ok(())
+  }
+
+  async fn set_facts(&self, path: &Path, changes: &[Change]) -> io::Result<()> {
+    let entry = self.entry(path)?;
+    let mut entry = lock(&entry);
+    for change in changes {
+      match change {
+        Change::Modify(time) => {
+          entry.modified = mfxx::parse_time(time).ok_or(io::ErrorKind::InvalidInput)?;
+        }
+        Change::Create(_) => return Err(io::ErrorKind::Unsupported.into()),
+        Change::UnixMode(mode) => entry.mode = *mode,
+      }
+    }
+    Ok(())
+  }
+}
+
+/// An open file, read and written at `position`.
+struct MemoryFile {
+  entry: Arc<Mutex<Entry>>,
+  position: u64,
+}
+
+impl AsyncRead for MemoryFile {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    _cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let entry = lock(&self.entry);
+    let start = (self.position as usize).min(entry.data.len());
+    let n = buf.remaining().min(entry.data.len() - start);
+    buf.put_slice(&entry.data[start..start + n]);
+    drop(entry);
+    self.position += n as u64;
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl AsyncWrite for MemoryFile {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    _cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    let start = self.position as usize;
+    let mut entry = lock(&self.entry);
+    if entry.data.len() < start + buf.len() {
+      entry.data.resize(start + buf.len(), 0);
+    }
+    entry.data[start..start + buf.len()].copy_from_slice(buf);
+    entry.modified = SystemTime::now();
+    drop(entry);
+    self.position += buf.len() as u64;
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[tokio::test]
+  async fn test_memory_backend() {
+    let storage = MemoryBackend::default();
+    let home = storage
+      .prepare_root(Path::new("./home/alice"))
+      .await
+      .unwrap();
+    assert_eq!(home, Path::new("/home/alice"));
+    assert!(storage.metadata(Path::new("/home")).await.unwrap().is_dir);
+
+    let file = home.join("a.txt");
+    let mut writer = storage.open_write(&file, None).await.unwrap();
+    writer.write_all(b"hello\n").await.unwrap();
+    writer.shutdown().await.unwrap();
+    let mut writer = storage.open_write(&file, Some(5)).await.unwrap();
+    writer.write_all(b"!\n").await.unwrap();
+    let mut data = String::new();
+    let mut reader = storage.open_read(&file, 1).await.unwrap();
+    reader.read_to_string(&mut data).await.unwrap();
+    assert_eq!(data, "ello!\n");
+    assert!(storage.open_write(&home.join("x/y"), None).await.is_err());
+
+    storage.create_dir(&home.join("docs")).await.unwrap();
+    storage
+      .rename(&file, &home.join("docs/b.txt"))
+      .await
+      .unwrap();
+    storage
+      .rename(&home.join("docs"), &home.join("d"))
+      .await
+      .unwrap();
+    let names = |entries: Vec<DirEntry>| {
+      entries
+        .into_iter()
+        .map(|entry| entry.name.into_string().unwrap())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(names(storage.list(&home).await.unwrap()), ["d"]);
+    assert_eq!(
+      names(storage.list(&home.join("d")).await.unwrap()),
+      ["b.txt"]
+    );
+    assert_eq!(
+      storage.metadata(&home.join("d/b.txt")).await.unwrap().len,
+      7
+    );
+
+    assert!(storage.remove_dir(&home.join("d")).await.is_err());
+    storage.remove_file(&home.join("d/b.txt")).await.unwrap();
+    storage.remove_dir(&home.join("d")).await.unwrap();
+    assert!(storage.list(&home).await.unwrap().is_empty());
+    assert!(storage.resolve(&home, PathBuf::from("/home/bob")).is_err());
+  }
+}
@@ -0,0 +1,175 @@
+mod archive;
+mod local;
+mod memory;
+mod s3;
+
+use async_trait::async_trait;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::arg_parser::{Args, StorageKind};
+use crate::lib::charset::Charset;
+use crate::lib::mfxx::Change;
+
+pub use archive::{pack, ArchiveBackend, PackFormat, PackMember};
+pub use local::LocalFsBackend;
+pub use memory::MemoryBackend;
+pub use s3::{S3Backend, S3Config};
+
+pub type FileReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type FileWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// What the handlers need to know about a file or directory.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+  pub is_dir: bool,
+  pub len: u64,
+  pub modified: Option<SystemTime>,
+  /// Unix permission bits, if the storage has them.
+  pub mode: Option<u32>,
+  /// Identifies the file for the `unique` fact, if the storage can.
+  pub unique: Option<String>,
+  /// Set on archives that can be entered like a read-only directory, see
+  /// `ArchiveBackend`.
+  pub archive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+  pub name: OsString,
+  pub metadata: Metadata,
+}
+
+/// Where the files served are kept. Paths are absolute paths within the
+/// storage, as returned by `resolve`.
+#[async_trait]
+pub trait StorageBackend: Debug + Send + Sync {
+  /// Where `path`, joined onto the user root `root`, is kept. Errors if it
+  /// leads outside of `root`.
+  fn resolve(&self, root: &Path, path: PathBuf) -> io::Result<PathBuf>;
+
+  /// Creates the directory `path` for use as a user root if it is missing,
+  /// and returns it in the form `resolve` expects.
+  async fn prepare_root(&self, path: &Path) -> io::Result<PathBuf>;
+
+  async fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+  async fn exists(&self, path: &Path) -> bool {
+    self.metadata(path).await.is_ok()
+  }
+
+  async fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+  /// Opens the file at `path` for reading from `offset` on.
+  async fn open_read(&self, path: &Path, offset: u64) -> io::Result<FileReader>;
+
+  /// Opens the existing file at `path` for writing from `offset` on, or
+  /// creates it if `offset` is `None`. The data is only stored for sure once
+  /// the writer is shut down.
+  async fn open_write(&self, path: &Path, offset: Option<u64>) -> io::Result<FileWriter>;
+
+  async fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+  async fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+  async fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+  async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+  /// Sets the times and mode of `path`, as asked by `MFMT` and `MFF`.
+  async fn set_facts(&self, path: &Path, changes: &[Change]) -> io::Result<()>;
+}
+
+/// Chunks buffered ahead of a `ChunkReader`.
+const READ_AHEAD: usize = 4;
+
+/// Data produced by a background task, e.g. a download, and sent over a
+/// channel chunk by chunk.
+struct ChunkReader {
+  chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+  chunk: Vec<u8>,
+  position: usize,
+}
+
+impl ChunkReader {
+  /// The reader and the sender the task feeds, which ends the data when it
+  /// is dropped.
+  fn new() -> (mpsc::Sender<io::Result<Vec<u8>>>, Self) {
+    let (sender, chunks) = mpsc::channel(READ_AHEAD);
+    let reader = Self {
+      chunks,
+      chunk: Vec::new(),
+      position: 0,
+    };
+    (sender, reader)
+  }
+}
+
+impl AsyncRead for ChunkReader {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    while self.position == self.chunk.len() {
+      match ready!(self.chunks.poll_recv(cx)) {
+        Some(Ok(chunk)) => {
+          self.chunk = chunk;
+          self.position = 0;
+        }
+        Some(Err(e)) => return Poll::Ready(Err(e)),
+        None => return Poll::Ready(Ok(())),
+      }
+    }
+    let n = buf.remaining().min(self.chunk.len() - self.position);
+    buf.put_slice(&self.chunk[self.position..self.position + n]);
+    self.position += n;
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// `path` as an absolute path without `.` and `..`, relative paths being
+/// taken from `/`.
+fn normalize(path: &Path) -> PathBuf {
+  let mut normalized = PathBuf::from("/");
+  for component in path.components() {
+    match component {
+      Component::Normal(name) => normalized.push(name),
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      _ => {}
+    }
+  }
+  normalized
+}
+
+/// Builds the storage selected on the command line.
+pub fn from_args(cfg: &Args, charset: Charset) -> Result<Arc<dyn StorageBackend>, Box<dyn Error>> {
+  Ok(match cfg.storage {
+    StorageKind::Local => Arc::new(LocalFsBackend::new(charset)),
+    StorageKind::Memory => Arc::new(MemoryBackend::default()),
+    StorageKind::S3 => {
+      let missing = |name: &str| format!("--{} is required with --storage s3", name);
+      Arc::new(S3Backend::new(S3Config {
+        endpoint: cfg.s3_endpoint.clone().ok_or(missing("s3-endpoint"))?,
+        bucket: cfg.s3_bucket.clone().ok_or(missing("s3-bucket"))?,
+        region: cfg.s3_region.clone(),
+        access_key: cfg.s3_access_key.clone().ok_or(missing("s3-access-key"))?,
+        secret_key: match &cfg.s3_secret_key {
+          Some(secret) => secret.0.clone(),
+          None => return Err(missing("s3-secret-key").into()),
+        },
+      }))
+    }
+  })
+}
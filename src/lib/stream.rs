@@ -0,0 +1,344 @@
+use async_compression::tokio::bufread::ZlibDecoder;
+use async_compression::tokio::write::ZlibEncoder;
+use async_compression::Level;
+use encoding_rs::Encoding;
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+
+use crate::lib::telnet::TelnetFilter;
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send + Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Debug> AsyncStream for T {}
+
+/// A control or data connection, either plain TCP or wrapped in TLS.
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// Plain TCP control connection. Reads go through `try_read`, since the
+/// `poll_read` of `TcpStream` takes a short read to mean that the socket was
+/// drained. That does not hold for the control connection, where a read stops
+/// at the urgent data some clients send along with `ABOR`.
+#[derive(Debug)]
+pub struct ControlStream(TcpStream);
+
+impl ControlStream {
+  pub fn new(stream: TcpStream) -> Self {
+    Self(stream)
+  }
+}
+
+impl AsyncRead for ControlStream {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    loop {
+      ready!(self.0.poll_read_ready(cx))?;
+      match self.0.try_read(buf.initialize_unfilled()) {
+        Ok(n) => {
+          buf.advance(n);
+          return Poll::Ready(Ok(()));
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+        Err(e) => return Poll::Ready(Err(e)),
+      }
+    }
+  }
+}
+
+impl AsyncWrite for ControlStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().0).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+  }
+}
+
+/// Longest command line accepted, including its line ending.
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+/// A line read from the control connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ControlLine {
+  /// A command without its line ending.
+  Command(Vec<u8>),
+  /// A line over `MAX_LINE_LENGTH`, which was discarded.
+  TooLong,
+}
+
+/// Read half of the control connection, split into `\r\n` terminated lines
+/// after Telnet commands are stripped. A command may arrive in pieces or
+/// together with the next ones, so partial lines are buffered.
+#[derive(Debug)]
+pub struct ControlReader {
+  inner: ReadHalf<BoxedStream>,
+  buf: Vec<u8>,
+  telnet: TelnetFilter,
+  /// Set while the rest of an overlong line is skipped.
+  discarding: bool,
+}
+
+impl ControlReader {
+  pub fn new(inner: ReadHalf<BoxedStream>) -> Self {
+    Self {
+      inner,
+      buf: Vec::new(),
+      telnet: TelnetFilter::new(),
+      discarding: false,
+    }
+  }
+
+  /// The next line, or `None` once the client closed the connection.
+  pub async fn next_line(&mut self) -> io::Result<Option<ControlLine>> {
+    loop {
+      if let Some(line) = self.split_line() {
+        return Ok(Some(line));
+      }
+      let mut chunk = [0; 2048];
+      let n = self.inner.read(&mut chunk).await?;
+      if n == 0 {
+        return Ok(None);
+      }
+      self.telnet.filter(&chunk[..n], &mut self.buf);
+    }
+  }
+
+  /// Gives the connection back for `AUTH TLS`. Whatever the client sent
+  /// after the `AUTH` command is dropped, since it was not protected.
+  pub fn into_inner(self) -> ReadHalf<BoxedStream> {
+    self.inner
+  }
+
+  fn split_line(&mut self) -> Option<ControlLine> {
+    match self.buf.iter().position(|byte| *byte == b'\n') {
+      Some(end) => {
+        let mut line = self.buf.drain(..=end).collect::<Vec<u8>>();
+        if std::mem::take(&mut self.discarding) || line.len() > MAX_LINE_LENGTH {
+          return Some(ControlLine::TooLong);
+        }
+        line.pop();
+        if line.last() == Some(&b'\r') {
+          line.pop();
+        }
+        Some(ControlLine::Command(line))
+      }
+      None => {
+        if self.buf.len() > MAX_LINE_LENGTH {
+          self.buf.clear();
+          self.discarding = true;
+        }
+        None
+      }
+    }
+  }
+}
+
+/// Write half of the control connection. `AUTH TLS` takes it out to rejoin
+/// the read half and upgrade the connection, then puts the new half back.
+#[derive(Debug)]
+pub struct ControlWriter {
+  inner: Option<WriteHalf<BoxedStream>>,
+  /// Charset the UTF-8 replies are converted to, `None` to send them as is.
+  encoding: Option<&'static Encoding>,
+  /// A converted reply being written, with the length of the original.
+  pending: Option<(Vec<u8>, usize)>,
+}
+
+impl ControlWriter {
+  pub fn new(inner: WriteHalf<BoxedStream>) -> Self {
+    Self {
+      inner: Some(inner),
+      encoding: None,
+      pending: None,
+    }
+  }
+
+  pub fn set_encoding(&mut self, encoding: Option<&'static Encoding>) {
+    self.encoding = encoding;
+  }
+
+  pub fn take(&mut self) -> Option<WriteHalf<BoxedStream>> {
+    self.inner.take()
+  }
+
+  pub fn replace(&mut self, inner: WriteHalf<BoxedStream>) {
+    self.inner = Some(inner);
+  }
+
+  fn inner(&mut self) -> io::Result<Pin<&mut WriteHalf<BoxedStream>>> {
+    Self::connected(&mut self.inner)
+  }
+
+  fn connected(
+    inner: &mut Option<WriteHalf<BoxedStream>>,
+  ) -> io::Result<Pin<&mut WriteHalf<BoxedStream>>> {
+    match inner.as_mut() {
+      Some(inner) => Ok(Pin::new(inner)),
+      None => Err(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "Control connection is being upgraded",
+      )),
+    }
+  }
+}
+
+impl AsyncWrite for ControlWriter {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let this = self.get_mut();
+    if this.pending.is_none() {
+      match this.encoding {
+        // Replies are only converted as a whole, so that a partial write
+        // never splits a character.
+        Some(encoding) if !buf.is_ascii() => {
+          let text = String::from_utf8_lossy(buf);
+          this.pending = Some((encoding.encode(&text).0.into_owned(), buf.len()));
+        }
+        _ => {
+          return match this.inner() {
+            Ok(inner) => inner.poll_write(cx, buf),
+            Err(e) => Poll::Ready(Err(e)),
+          }
+        }
+      }
+    }
+    while let Some((converted, len)) = this.pending.as_mut() {
+      if converted.is_empty() {
+        let len = *len;
+        this.pending = None;
+        return Poll::Ready(Ok(len));
+      }
+      let written = match Self::connected(&mut this.inner) {
+        Ok(inner) => ready!(inner.poll_write(cx, converted)),
+        Err(e) => Err(e),
+      };
+      match written {
+        Ok(0) => {
+          this.pending = None;
+          return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        Ok(n) => {
+          converted.drain(..n);
+        }
+        Err(e) => {
+          this.pending = None;
+          return Poll::Ready(Err(e));
+        }
+      }
+    }
+    Poll::Ready(Ok(0))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut().inner() {
+      Ok(inner) => inner.poll_flush(cx),
+      Err(e) => Poll::Ready(Err(e)),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut().inner() {
+      Ok(inner) => inner.poll_shutdown(cx),
+      Err(e) => Poll::Ready(Err(e)),
+    }
+  }
+}
+
+/// Data connection of a `MODE Z` transfer, deflating what is written and
+/// inflating what is read. A transfer only goes one way, so each direction
+/// gets its own codec around one half of the connection.
+#[derive(Debug)]
+pub struct DeflateStream {
+  reader: ZlibDecoder<BufReader<ReadHalf<BoxedStream>>>,
+  writer: ZlibEncoder<WriteHalf<BoxedStream>>,
+  /// Set once data was read, so an upload does not answer with an empty
+  /// compressed stream when it closes the connection.
+  inbound: bool,
+}
+
+impl DeflateStream {
+  pub fn new(stream: BoxedStream, level: u32) -> Self {
+    let (reader, writer) = tokio::io::split(stream);
+    Self {
+      reader: ZlibDecoder::new(BufReader::new(reader)),
+      writer: ZlibEncoder::with_quality(writer, Level::Precise(level as i32)),
+      inbound: false,
+    }
+  }
+}
+
+impl AsyncRead for DeflateStream {
+  fn poll_read(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    this.inbound = true;
+    Pin::new(&mut this.reader).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for DeflateStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+  }
+
+  /// Finishes the compressed stream before closing the connection.
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    if this.inbound {
+      Pin::new(this.writer.get_mut()).poll_shutdown(cx)
+    } else {
+      Pin::new(&mut this.writer).poll_shutdown(cx)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::AsyncWriteExt;
+
+  #[tokio::test]
+  async fn test_control_reader() {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let (reader, _writer) = tokio::io::split(Box::new(server) as BoxedStream);
+    let mut reader = ControlReader::new(reader);
+    let (_, mut client) = tokio::io::split(client);
+
+    client.write_all(b"USER alice\r\nPA").await.unwrap();
+    let line = reader.next_line().await.unwrap();
+    assert_eq!(line, Some(ControlLine::Command(b"USER alice".to_vec())));
+    client.write_all(b"SS secret\r\nNOOP\n").await.unwrap();
+    let line = reader.next_line().await.unwrap();
+    assert_eq!(line, Some(ControlLine::Command(b"PASS secret".to_vec())));
+    let line = reader.next_line().await.unwrap();
+    assert_eq!(line, Some(ControlLine::Command(b"NOOP".to_vec())));
+
+    let long = vec![b'A'; MAX_LINE_LENGTH * 3];
+    client.write_all(&long).await.unwrap();
+    client.write_all(b"\r\nPWD\r\n").await.unwrap();
+    let line = reader.next_line().await.unwrap();
+    assert_eq!(line, Some(ControlLine::TooLong));
+    let line = reader.next_line().await.unwrap();
+    assert_eq!(line, Some(ControlLine::Command(b"PWD".to_vec())));
+
+    drop(client);
+    assert_eq!(reader.next_line().await.unwrap(), None);
+  }
+}
@@ -0,0 +1,82 @@
+// Telnet commands (RFC 854) that may show up on the control connection.
+const IAC: u8 = 255;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+  #[default]
+  Data,
+  Command,
+  Option,
+  Subnegotiation,
+  SubnegotiationCommand,
+}
+
+/// Strips Telnet commands from what the client sends on the control
+/// connection, like the `IAC IP IAC DM` sent before `ABOR`. Option
+/// negotiation is ignored and `IAC IAC` is a literal `0xff` byte. The state
+/// is kept between reads, as a command may be split across them.
+#[derive(Debug, Default)]
+pub struct TelnetFilter {
+  state: State,
+}
+
+impl TelnetFilter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Appends the data bytes of `input` to `output`.
+  pub fn filter(&mut self, input: &[u8], output: &mut Vec<u8>) {
+    for &byte in input {
+      self.state = match (self.state, byte) {
+        (State::Data, IAC) => State::Command,
+        (State::Data, _) => {
+          output.push(byte);
+          State::Data
+        }
+        (State::Command, IAC) => {
+          output.push(IAC);
+          State::Data
+        }
+        (State::Command, SB) => State::Subnegotiation,
+        (State::Command, WILL..=DONT) => State::Option,
+        // IP, DM, AO, AYT and the other two byte commands.
+        (State::Command, _) => State::Data,
+        (State::Option, _) => State::Data,
+        (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+        (State::Subnegotiation, _) => State::Subnegotiation,
+        (State::SubnegotiationCommand, SE) => State::Data,
+        (State::SubnegotiationCommand, _) => State::Subnegotiation,
+      };
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filter(input: &[&[u8]]) -> Vec<u8> {
+    let mut filter = TelnetFilter::new();
+    let mut output = Vec::new();
+    for chunk in input {
+      filter.filter(chunk, &mut output);
+    }
+    output
+  }
+
+  #[test]
+  fn test_telnet_filter() {
+    assert_eq!(filter(&[b"\xff\xf4\xff\xf2ABOR\r\n"]), b"ABOR\r\n");
+    assert_eq!(filter(&[b"\xff", b"\xf4\xff", b"\xf2ABOR"]), b"ABOR");
+    assert_eq!(filter(&[b"RETR \xff\xff.txt"]), b"RETR \xff.txt");
+    assert_eq!(
+      filter(&[b"\xff\xfb\x01NO\xff\xfa\x18\x01\xff\xf0OP"]),
+      b"NOOP"
+    );
+  }
+}
@@ -0,0 +1,108 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ServerSessionMemoryCache, StoresServerSessions};
+use rustls::{HandshakeKind, ServerConfig};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Sessions a single control connection keeps around for its data connections.
+const SESSION_CACHE_SIZE: usize = 16;
+
+/// TLS requirements set on the command line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlsPolicy {
+  /// Refuse `USER` and `PASS` before `AUTH TLS`.
+  pub require_auth: bool,
+  /// Refuse data transfers under `PROT C`.
+  pub require_prot: bool,
+  /// Refuse data connections that do not resume the control connection's
+  /// TLS session, so nobody else can hijack them.
+  pub require_reuse: bool,
+}
+
+/// Builds the TLS config from a PEM certificate chain and a PEM private key.
+pub fn load_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>, Box<dyn Error>> {
+  let certs = CertificateDer::pem_file_iter(cert_path)
+    .map_err(|e| format!("Failed to read certificate {}: {}", cert_path, e))?
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| format!("Invalid certificate {}: {}", cert_path, e))?;
+  let key = PrivateKeyDer::from_pem_file(key_path)
+    .map_err(|e| format!("Failed to read private key {}: {}", key_path, e))?;
+
+  let config =
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+      .with_safe_default_protocol_versions()?
+      .with_no_client_auth()
+      .with_single_cert(certs, key)?;
+  Ok(Arc::new(config))
+}
+
+/// Session cache of a single control connection. TLS 1.3 tickets are meant to
+/// be used once, but clients resume the control connection's session for
+/// every data connection, so `take` leaves it in place.
+#[derive(Debug)]
+struct ReusableSessions(Arc<ServerSessionMemoryCache>);
+
+impl StoresServerSessions for ReusableSessions {
+  fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+    self.0.put(key, value)
+  }
+
+  fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    self.0.get(key)
+  }
+
+  fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+    self.0.get(key)
+  }
+
+  fn can_cache(&self) -> bool {
+    self.0.can_cache()
+  }
+}
+
+/// Copies of `config` for one control connection and for its data
+/// connections, sharing a session cache of their own. A data connection
+/// resuming a session from this cache therefore comes from the client of that
+/// control connection.
+pub fn session_configs(config: &Arc<ServerConfig>) -> (Arc<ServerConfig>, Arc<ServerConfig>) {
+  let mut control = (**config).clone();
+  control.session_storage = Arc::new(ReusableSessions(ServerSessionMemoryCache::new(
+    SESSION_CACHE_SIZE,
+  )));
+  // Data connections resume instead of issuing tickets that would push the
+  // control connection's session out of the cache.
+  let mut data = control.clone();
+  data.send_tls13_tickets = 0;
+  (Arc::new(control), Arc::new(data))
+}
+
+/// How to accept the TLS handshake of a data connection.
+#[derive(Clone)]
+pub struct DataTls {
+  acceptor: TlsAcceptor,
+  require_reuse: bool,
+}
+
+impl DataTls {
+  pub fn new(config: Arc<ServerConfig>, require_reuse: bool) -> Self {
+    Self {
+      acceptor: TlsAcceptor::from(config),
+      require_reuse,
+    }
+  }
+
+  pub async fn accept(
+    &self,
+    stream: TcpStream,
+  ) -> Result<TlsStream<TcpStream>, Box<dyn Error + Send + Sync>> {
+    let stream = self.acceptor.accept(stream).await?;
+    if self.require_reuse && stream.get_ref().1.handshake_kind() != Some(HandshakeKind::Resumed) {
+      return Err("TLS session of the control connection was not reused".into());
+    }
+    Ok(stream)
+  }
+}
@@ -1,11 +1,21 @@
+use crate::lib::auth::Account;
+use crate::lib::charset::Charset;
+use crate::lib::hash::HashAlgorithm;
+use crate::lib::mlsx::Fact;
 use crate::lib::session::TransferSession;
+use crate::lib::storage::StorageBackend;
+use rustls::ServerConfig;
+use std::borrow::Cow;
 use std::error::Error;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-#[derive(Debug)]
+/// Compression level of `MODE Z` transfers until `OPTS MODE Z LEVEL`.
+pub const DEFAULT_DEFLATE_LEVEL: u32 = 6;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum UserStatus {
   Inactive,
   Logging,
@@ -13,58 +23,148 @@ pub enum UserStatus {
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum TransferType {
   ASCII,
   Binary,
 }
 
+/// Transmission mode selected with `MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransmissionMode {
+  Stream,
+  Block,
+  Deflate,
+}
+
+/// Data channel protection level selected with `PROT`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DataProtection {
+  Clear,
+  Private,
+}
+
 #[derive(Debug)]
 pub struct User {
   pub username: String,
   pub status: UserStatus,
   pub addr: SocketAddr,
+  /// Server side address of the control connection.
+  pub local_addr: SocketAddr,
   pub session: Option<Arc<Mutex<TransferSession>>>,
   pub trans_type: TransferType,
-  
+  pub account: Option<Account>,
+  /// TLS config for the data connections, set once the control connection
+  /// is wrapped in TLS.
+  pub tls: Option<Arc<ServerConfig>>,
+  pub pbsz: bool,
+  pub prot: DataProtection,
+  /// Set by `EPSV ALL`, after which only `EPSV` may set up data connections.
+  pub epsv_all: bool,
+  /// Facts included in `MLSD` and `MLST` entries, chosen with `OPTS MLST`.
+  pub mlst_facts: Vec<Fact>,
+  pub mode: TransmissionMode,
+  /// Compression level of `MODE Z`, set with `OPTS MODE Z LEVEL`.
+  pub deflate_level: u32,
+  /// Algorithm of `HASH`, chosen with `OPTS HASH`.
+  pub hash_algorithm: HashAlgorithm,
+  /// Byte range set with `RANG` for the next `HASH`.
+  pub hash_range: Option<(u64, u64)>,
+  /// Set by `OPTS UTF8 ON`, after which names are sent in UTF-8 even if a
+  /// fallback charset is configured.
+  pub utf8: bool,
+  /// Bytes stored below the root, counted on the first upload under a quota
+  /// and kept up to date by the uploads and deletions of this session.
+  pub used_space: Option<u64>,
+
   path: PathGuard,
+  charset: Charset,
 }
 
 impl User {
-  pub fn cwd(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
-    self.path.cwd(path)
+  pub async fn cwd(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+    self.path.cwd(path).await
   }
 
-  pub fn pwd(&self) -> String {
-    self.path.pwd().replace("/", "")
-  }
-  
   pub fn rendering_pwd(&self) -> String {
-    self.path.pwd()
+    format!("/{}", self.path.pwd().trim_start_matches('/'))
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.path.root
+  }
+
+  /// Jails the user to `root`, e.g. its home directory after `PASS`. The
+  /// root must come from `StorageBackend::prepare_root`.
+  pub fn set_root(&mut self, root: PathBuf) {
+    self.path = PathGuard::new(root, self.path.storage.clone());
+    self.used_space = None;
+  }
+
+  pub fn charset(&self) -> Charset {
+    self.charset
+  }
+
+  pub fn set_charset(&mut self, charset: Charset) {
+    self.charset = charset;
+  }
+
+  /// Encodes a listing or reply in the charset the client expects.
+  pub fn encode<'a>(&self, text: &'a str) -> Cow<'a, [u8]> {
+    self.charset.encode(text, self.utf8)
+  }
+
+  /// Absolute path inside the user root of `path`, relative to the pwd.
+  pub fn virtual_path(&self, path: &str) -> String {
+    self.path.virtual_path(path)
+  }
+
+  /// Location in the storage of `path`, refused if it escapes the user root.
+  pub fn resolve(&self, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    self.path.real_path(path)
   }
 
-  pub fn new(username: String, addr: SocketAddr, root: &String) -> Result<Self, Box<dyn Error>>{
-    Ok(Self {
+  pub fn new(
+    username: String,
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    root: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+  ) -> Self {
+    Self {
       addr,
+      local_addr,
       username,
       session: None,
-      path: PathGuard::new(root)?,
-      status: UserStatus::Logging,
+      path: PathGuard::new(root, storage),
+      status: UserStatus::Inactive,
       trans_type: TransferType::ASCII,
-    })
+      account: None,
+      tls: None,
+      pbsz: false,
+      prot: DataProtection::Clear,
+      epsv_all: false,
+      mlst_facts: Fact::ALL.to_vec(),
+      mode: TransmissionMode::Stream,
+      deflate_level: DEFAULT_DEFLATE_LEVEL,
+      hash_algorithm: HashAlgorithm::Sha256,
+      hash_range: None,
+      utf8: false,
+      used_space: None,
+      charset: Charset::default(),
+    }
   }
 
-  pub fn new_anonymous(addr: SocketAddr, root: &String) -> Result<Self, Box<dyn Error>> {
-    Ok(Self {
-      addr,
-      username: String::from("anonymous"),
-      session: None,
-      path: PathGuard::new(root)?,
-      status: UserStatus::Active,
-      trans_type: TransferType::ASCII,
-    })
+  pub fn is_logged_in(&self) -> bool {
+    self.status == UserStatus::Active
   }
 
-  pub fn set_new_session(&mut self, session: TransferSession) {
+  pub fn is_secure(&self) -> bool {
+    self.tls.is_some()
+  }
+
+  pub fn set_new_session(&mut self, mut session: TransferSession) {
+    session.deflate_level = (self.mode == TransmissionMode::Deflate).then_some(self.deflate_level);
     self.session = Some(Arc::new(Mutex::new(session)));
   }
 
@@ -78,55 +178,66 @@ impl User {
 
 #[derive(Debug)]
 struct PathGuard {
-  root: String,
+  root: PathBuf,
   pub pwd: String,
+  storage: Arc<dyn StorageBackend>,
 }
 
 impl PathGuard {
-  pub fn new(root: &String) -> Result<Self, Box<dyn Error>> {
-    Ok(Self {
-      root: match Path::new(root.as_str()).canonicalize()?.to_str() {
-        Some(s) => s.to_string(),
-        None => return Err("Invalid root path".into()),
-      },
+  pub fn new(root: PathBuf, storage: Arc<dyn StorageBackend>) -> Self {
+    Self {
+      root,
       pwd: String::new(),
-    })
+      storage,
+    }
   }
 
-  pub fn cwd(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+  pub async fn cwd(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
     if path == "." {
       return Ok(());
     }
 
-    let mut path_buf = if path.starts_with("/") {
-      Path::new(self.root.as_str()).join(path.trim_start_matches("/"))
-    } else {
-      Path::new(self.root.as_str())
-        .join(self.pwd.as_str())
-        .join(path)
-    };
-
-    if !path_buf.exists() {
+    let path_buf = self.real_path(path)?;
+    if !self
+      .storage
+      .metadata(&path_buf)
+      .await
+      .is_ok_and(|metadata| metadata.is_dir || metadata.archive)
+    {
       return Err("Path not found".into());
     }
+    self.pwd = self.virtual_path(path).trim_start_matches('/').to_string();
+    Ok(())
+  }
 
-    path_buf = path_buf.canonicalize()?;
-
-    if !path_buf.starts_with(self.root.as_str()) {
-      return Err("Path not allowed".into());
+  /// Normalizes `path` against the pwd into an absolute virtual path. `..`
+  /// never climbs above the root, like in a chroot.
+  pub fn virtual_path(&self, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+      Vec::new()
+    } else {
+      self.pwd.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for part in path.split('/') {
+      match part {
+        "" | "." => {}
+        ".." => {
+          parts.pop();
+        }
+        part => parts.push(part),
+      }
     }
-    self.pwd = path_buf
-      .to_str()
-      .unwrap()
-      .to_string()
-      .replace(self.root.as_str(), "")
-      .trim_start_matches("/")
-      .to_string();
-    Ok(())
+    format!("/{}", parts.join("/"))
+  }
+
+  pub fn real_path(&self, path: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let virtual_path = self.virtual_path(path);
+    let real = self.root.join(virtual_path.trim_start_matches('/'));
+    Ok(self.storage.resolve(&self.root, real)?)
   }
 
   pub fn pwd(&self) -> String {
-    if self.pwd == "" {
+    if self.pwd.is_empty() {
       "/".to_string()
     } else {
       self.pwd.clone()
@@ -137,46 +248,46 @@ impl PathGuard {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::lib::storage::MemoryBackend;
 
-  #[test]
-  fn test_path_guard() {
-    let test_root = String::from("/tmp/test_root");
-    let child_folder = String::from("/tmp/test_root/test/test");
+  #[tokio::test]
+  async fn test_path_guard() {
+    let storage = Arc::new(MemoryBackend::default());
+    let root = storage.prepare_root(Path::new("/test_root")).await.unwrap();
+    storage
+      .prepare_root(Path::new("/test_root/test/test"))
+      .await
+      .unwrap();
 
-    if let Err(e) = std::fs::create_dir_all(&child_folder) {
-      panic!("Failed to create test root: {}", e);
-    }
-
-    let mut pg = PathGuard::new(&test_root).unwrap();
+    let mut pg = PathGuard::new(root, storage);
     assert_eq!(pg.pwd(), "/");
 
-    pg.cwd("test").unwrap();
+    pg.cwd("test").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("test").unwrap();
+    pg.cwd("test").await.unwrap();
     assert_eq!(pg.pwd(), "test/test");
-    pg.cwd("..").unwrap();
+    pg.cwd("..").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("..").unwrap();
+    pg.cwd("..").await.unwrap();
     assert_eq!(pg.pwd(), "/");
-    pg.cwd("test").unwrap();
+    pg.cwd("test").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("/").unwrap();
+    pg.cwd("/").await.unwrap();
     assert_eq!(pg.pwd(), "/");
-    pg.cwd("test").unwrap();
+    pg.cwd("test").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("/test").unwrap();
+    pg.cwd("/test").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("/").unwrap();
+    pg.cwd("/").await.unwrap();
     assert_eq!(pg.pwd(), "/");
-    pg.cwd("test").unwrap();
+    pg.cwd("test").await.unwrap();
     assert_eq!(pg.pwd(), "test");
-    pg.cwd("/test/test").unwrap();
+    pg.cwd("/test/test").await.unwrap();
     assert_eq!(pg.pwd(), "test/test");
-    pg.cwd("/test/..").unwrap();
+    pg.cwd("/test/..").await.unwrap();
     assert_eq!(pg.pwd(), "/");
 
-    pg.cwd("test3").unwrap_err();
-    pg.cwd("/tmp").unwrap_err();
-
+    pg.cwd("test3").await.unwrap_err();
+    pg.cwd("/tmp").await.unwrap_err();
   }
 }
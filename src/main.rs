@@ -1,11 +1,22 @@
+#![allow(special_module_name)]
+
 mod arg_parser;
 mod lib;
 
+use lib::auth::hash_password;
 use lib::server::Server;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
   let args = arg_parser::Args::parse_args();
+
+  if let Some(password) = &args.hash_password {
+    match hash_password(password) {
+      Ok(hash) => println!("{}", hash),
+      Err(e) => println!("Failed to hash password: {}", e),
+    }
+    return Ok(());
+  }
   println!("{args:?}");
 
   let server = Server::new(args).await?;